@@ -16,13 +16,32 @@ use std::io::Read;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use zatel::{
-    ipc_connect, ipc_exec, ZatelConnection, ZatelIpcData, ZatelIpcMessage,
+    connect_transport, default_server_addr, ipc_exec, ipc_handshake_client,
+    ZatelConnection, ZatelError, ZatelIpcData, ZatelIpcMessage,
+    ZatelIpcStream, ZatelLogEntry, ZatelServerAddr, ZatelStream,
 };
 
 #[tokio::main]
 async fn main() {
     let matches = App::new("ztl")
         .about("CLI to Zatel daemon")
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .global(true)
+                .help("show diagnostics logged by plugins while handling this request"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .takes_value(true)
+                .global(true)
+                .help(
+                    "daemon to connect to: a Unix socket path/name (default), \
+                    tcp://host:port, or tls://host:port",
+                ),
+        )
         .subcommand(
             SubCommand::with_name("query")
                 .about("Query interface information")
@@ -57,7 +76,7 @@ async fn main() {
                             Arg::with_name("conn_id")
                                 .index(1)
                                 .required(true)
-                                //TODO: .multiple(true)
+                                .multiple(true)
                                 .help("show specific connections only"),
                         ),
                 )
@@ -75,22 +94,154 @@ async fn main() {
                                 .required(true)
                                 .help("YAML file for connection to add"),
                         ),
+                )
+                .subcommand(
+                    SubCommand::with_name("export")
+                        .about("Export a saved connection's config")
+                        .alias("e")
+                        .alias("ex")
+                        .alias("exp")
+                        .alias("expo")
+                        .alias("expor")
+                        .arg(
+                            Arg::with_name("conn_id")
+                                .index(1)
+                                .required(true)
+                                .help("UUID of the connection to export"),
+                        )
+                        .arg(
+                            Arg::with_name("file_path")
+                                .index(2)
+                                .help(
+                                    "file to write the YAML to \
+                                    (default: stdout)",
+                                ),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete a saved connection")
+                        .alias("d")
+                        .alias("de")
+                        .alias("del")
+                        .alias("dele")
+                        .alias("delet")
+                        .arg(
+                            Arg::with_name("conn_id")
+                                .index(1)
+                                .required(true)
+                                .help("UUID of the connection to delete"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("up")
+                        .about("Activate a saved connection")
+                        .arg(
+                            Arg::with_name("conn_id")
+                                .index(1)
+                                .required(true)
+                                .help("UUID of the connection to activate"),
+                        ),
+                )
+                .subcommand(
+                    SubCommand::with_name("down")
+                        .about("Deactivate a saved connection")
+                        .arg(
+                            Arg::with_name("conn_id")
+                                .index(1)
+                                .required(true)
+                                .help("UUID of the connection to deactivate"),
+                        ),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("reload")
+                .about("Reload plugins without restarting the daemon")
+                .alias("r")
+                .alias("re")
+                .alias("rel")
+                .alias("relo")
+                .alias("reloa"),
+        )
+        .subcommand(
+            SubCommand::with_name("firewall")
+                .about("Declarative firewall rule sets")
+                .alias("f")
+                .alias("fw")
+                .subcommand(
+                    SubCommand::with_name("query")
+                        .about("Show the currently applied firewall rules"),
+                )
+                .subcommand(
+                    SubCommand::with_name("apply")
+                        .about("Apply a new firewall rule set from file")
+                        .arg(
+                            Arg::with_name("file_path")
+                                .index(1)
+                                .required(true)
+                                .help("YAML file of firewall rules to apply"),
+                        ),
                 ),
         )
         .get_matches();
 
+    let verbose = matches.is_present("verbose");
+    let server_addr = match matches.value_of("server") {
+        Some(s) => match ZatelServerAddr::parse(s) {
+            Ok(a) => a,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        None => default_server_addr(),
+    };
+
     if let Some(matches) = matches.subcommand_matches("query") {
-        handle_query(&matches).await;
+        handle_query(&matches, &server_addr, verbose).await;
     } else if let Some(matches) = matches.subcommand_matches("connection") {
-        handle_connection(&matches).await;
+        handle_connection(&matches, &server_addr, verbose).await;
+    } else if matches.subcommand_matches("reload").is_some() {
+        handle_reload(&server_addr, verbose).await;
+    } else if let Some(matches) = matches.subcommand_matches("firewall") {
+        handle_firewall(&matches, &server_addr, verbose).await;
     } else {
         eprintln!("TODO: show all network state in brief summery");
     }
 }
 
-async fn handle_query(matches: &ArgMatches<'_>) {
+// Connect to the daemon at `server_addr` (Unix socket by default, or
+// tcp://.../tls://... per --server) and complete the wire-format
+// handshake, so every handler below can talk to it the same way
+// regardless of which transport was selected.
+async fn connect(
+    server_addr: &ZatelServerAddr,
+) -> Result<ZatelIpcStream<ZatelStream>, ZatelError> {
+    let stream = connect_transport(server_addr).await?;
+    ipc_handshake_client(stream).await
+}
+
+// Print each plugin's log entries (already prefixed with the plugin's
+// name by the daemon) when -v/--verbose was passed, so an operator can
+// see which plugin produced which diagnostic for this request.
+fn print_plugin_logs(verbose: bool, log: &Option<Vec<ZatelLogEntry>>) {
+    if !verbose {
+        return;
+    }
+    if let Some(entries) = log {
+        for entry in entries {
+            eprintln!("{:?}: {}", entry.level, entry.message);
+        }
+    }
+}
+
+async fn handle_query(
+    matches: &ArgMatches<'_>,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
     let iface_name = matches.value_of("iface_name").unwrap();
-    let mut connection = ipc_connect().await.unwrap();
+    let mut connection = connect(server_addr).await.unwrap();
     match ipc_exec(
         &mut connection,
         &ZatelIpcMessage::new(ZatelIpcData::QueryIfaceInfo(
@@ -101,26 +252,163 @@ async fn handle_query(matches: &ArgMatches<'_>) {
     {
         Ok(ZatelIpcMessage {
             data: ZatelIpcData::QueryIfaceInfoReply(s),
-            log: _,
-        }) => println!("{}", s),
+            request_id: _,
+            log,
+        }) => {
+            print_plugin_logs(verbose, &log);
+            println!("{}", s);
+        }
         Ok(i) => eprintln!("Unknown reply: {:?}", i),
         Err(e) => eprintln!("{}", e),
     }
 }
 
-async fn handle_connection(matches: &ArgMatches<'_>) {
+async fn handle_reload(server_addr: &ZatelServerAddr, verbose: bool) {
+    let mut connection = connect(server_addr).await.unwrap();
+    match ipc_exec(
+        &mut connection,
+        &ZatelIpcMessage::new(ZatelIpcData::ReloadPlugins),
+    )
+    .await
+    {
+        Ok(ZatelIpcMessage {
+            data: ZatelIpcData::ReloadPluginsReply { added, removed, failed },
+            request_id: _,
+            log,
+        }) => {
+            print_plugin_logs(verbose, &log);
+            println!("added:   {:?}", added);
+            println!("removed: {:?}", removed);
+            if !failed.is_empty() {
+                println!("failed:  {:?}", failed);
+            }
+        }
+        Ok(i) => eprintln!("Unknown reply: {:?}", i),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+async fn handle_firewall(
+    matches: &ArgMatches<'_>,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    if let Some(matches) = matches.subcommand_matches("apply") {
+        handle_firewall_apply(
+            matches.value_of("file_path").unwrap(),
+            server_addr,
+            verbose,
+        )
+        .await;
+    } else {
+        handle_firewall_query(server_addr, verbose).await;
+    }
+}
+
+async fn handle_firewall_query(server_addr: &ZatelServerAddr, verbose: bool) {
+    let mut connection = connect(server_addr).await.unwrap();
+    match ipc_exec(
+        &mut connection,
+        &ZatelIpcMessage::new(ZatelIpcData::QueryFirewallRules),
+    )
+    .await
+    {
+        Ok(ZatelIpcMessage {
+            data: ZatelIpcData::QueryFirewallRulesReply(s),
+            request_id: _,
+            log,
+        }) => {
+            print_plugin_logs(verbose, &log);
+            println!("{}", s);
+        }
+        Ok(i) => eprintln!("Unknown reply: {:?}", i),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+async fn handle_firewall_apply(
+    file_path: &str,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let rules = read_file(file_path);
+    let mut connection = connect(server_addr).await.unwrap();
+    match ipc_exec(
+        &mut connection,
+        &ZatelIpcMessage::new(ZatelIpcData::ApplyFirewallRules(rules)),
+    )
+    .await
+    {
+        Ok(ZatelIpcMessage {
+            data: ZatelIpcData::ApplyFirewallRulesReply(s),
+            request_id: _,
+            log,
+        }) => {
+            print_plugin_logs(verbose, &log);
+            println!("{}", s);
+        }
+        Ok(i) => eprintln!("Unknown reply: {:?}", i),
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+async fn handle_connection(
+    matches: &ArgMatches<'_>,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
     if let Some(matches) = matches.subcommand_matches("show") {
-        handle_connection_show(matches.value_of("conn_id").unwrap()).await;
+        let uuids: Vec<&str> =
+            matches.values_of("conn_id").unwrap().collect();
+        handle_connection_show(&uuids, server_addr, verbose).await;
     } else if let Some(matches) = matches.subcommand_matches("import") {
-        handle_connection_import(matches.value_of("file_path").unwrap()).await;
+        handle_connection_import(
+            matches.value_of("file_path").unwrap(),
+            server_addr,
+            verbose,
+        )
+        .await;
+    } else if let Some(matches) = matches.subcommand_matches("export") {
+        handle_connection_export(
+            matches.value_of("conn_id").unwrap(),
+            matches.value_of("file_path"),
+            server_addr,
+            verbose,
+        )
+        .await;
+    } else if let Some(matches) = matches.subcommand_matches("delete") {
+        handle_connection_delete(
+            matches.value_of("conn_id").unwrap(),
+            server_addr,
+            verbose,
+        )
+        .await;
+    } else if let Some(matches) = matches.subcommand_matches("up") {
+        handle_connection_up(
+            matches.value_of("conn_id").unwrap(),
+            server_addr,
+            verbose,
+        )
+        .await;
+    } else if let Some(matches) = matches.subcommand_matches("down") {
+        handle_connection_down(
+            matches.value_of("conn_id").unwrap(),
+            server_addr,
+            verbose,
+        )
+        .await;
     } else {
-        handle_connection_show_all().await;
+        handle_connection_show_all(server_addr, verbose).await;
     }
 }
 
-async fn handle_connection_import(file_path: &str) {
+async fn handle_connection_import(
+    file_path: &str,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
     let content = read_file(file_path);
-    let mut connection = ipc_connect().await.unwrap();
+    let mut connection = connect(server_addr).await.unwrap();
     let ztl_con = ZatelConnection::new(content);
     match ipc_exec(
         &mut connection,
@@ -129,6 +417,7 @@ async fn handle_connection_import(file_path: &str) {
     .await
     {
         Ok(r) => {
+            print_plugin_logs(verbose, &r.log);
             if let ZatelIpcData::SaveConfReply(new_ztl_con) = &r.data {
                 println!("Connection saved:");
                 print_connection(&new_ztl_con);
@@ -149,8 +438,15 @@ fn read_file(file_path: &str) -> String {
     contents
 }
 
-async fn handle_connection_show_all() {
-    let mut connection = ipc_connect().await.unwrap();
+fn write_file(file_path: &str, content: &str) {
+    std::fs::write(file_path, content).expect("Failed to write file");
+}
+
+async fn handle_connection_show_all(
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let mut connection = connect(server_addr).await.unwrap();
     match ipc_exec(
         &mut connection,
         &ZatelIpcMessage::new(ZatelIpcData::QuerySavedConfAll),
@@ -158,6 +454,7 @@ async fn handle_connection_show_all() {
     .await
     {
         Ok(r) => {
+            print_plugin_logs(verbose, &r.log);
             if let ZatelIpcData::QuerySavedConfAllReply(ztl_cons) = r.data {
                 println!("{:>36} | name", "UUID              ");
                 for ztl_con in ztl_cons {
@@ -182,8 +479,42 @@ fn print_connection(ztl_con: &ZatelConnection) {
     println!("{}", &ztl_con.config);
 }
 
-async fn handle_connection_show(uuid: &str) {
-    let mut connection = ipc_connect().await.unwrap();
+async fn handle_connection_show(
+    uuids: &[&str],
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let mut connection = connect(server_addr).await.unwrap();
+    for uuid in uuids {
+        match ipc_exec(
+            &mut connection,
+            &ZatelIpcMessage::new(ZatelIpcData::QuerySavedConf(
+                uuid.to_string(),
+            )),
+        )
+        .await
+        {
+            Ok(r) => {
+                print_plugin_logs(verbose, &r.log);
+                if let ZatelIpcData::QuerySavedConfReply(ztl_con) = &r.data {
+                    print_connection(&ztl_con);
+                    println!("");
+                } else {
+                    eprintln!("Unexpected reply {:?}", r);
+                }
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+async fn handle_connection_export(
+    uuid: &str,
+    file_path: Option<&str>,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let mut connection = connect(server_addr).await.unwrap();
     match ipc_exec(
         &mut connection,
         &ZatelIpcMessage::new(ZatelIpcData::QuerySavedConf(uuid.to_string())),
@@ -191,7 +522,86 @@ async fn handle_connection_show(uuid: &str) {
     .await
     {
         Ok(r) => {
+            print_plugin_logs(verbose, &r.log);
             if let ZatelIpcData::QuerySavedConfReply(ztl_con) = &r.data {
+                match file_path {
+                    Some(p) => write_file(p, &ztl_con.config),
+                    None => println!("{}", &ztl_con.config),
+                }
+            } else {
+                eprintln!("Unexpected reply {:?}", r);
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+async fn handle_connection_delete(
+    uuid: &str,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let mut connection = connect(server_addr).await.unwrap();
+    match ipc_exec(
+        &mut connection,
+        &ZatelIpcMessage::new(ZatelIpcData::DeleteConf(uuid.to_string())),
+    )
+    .await
+    {
+        Ok(r) => {
+            print_plugin_logs(verbose, &r.log);
+            if let ZatelIpcData::DeleteConfReply(deleted_uuid) = &r.data {
+                println!("Connection {} deleted", deleted_uuid);
+            } else {
+                eprintln!("Unexpected reply {:?}", r);
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+async fn handle_connection_up(
+    uuid: &str,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let mut connection = connect(server_addr).await.unwrap();
+    match ipc_exec(
+        &mut connection,
+        &ZatelIpcMessage::new(ZatelIpcData::ActivateConf(uuid.to_string())),
+    )
+    .await
+    {
+        Ok(r) => {
+            print_plugin_logs(verbose, &r.log);
+            if let ZatelIpcData::ActivateConfReply(ztl_con) = &r.data {
+                println!("Connection activated:");
+                print_connection(&ztl_con);
+                println!("");
+            } else {
+                eprintln!("Unexpected reply {:?}", r);
+            }
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
+
+async fn handle_connection_down(
+    uuid: &str,
+    server_addr: &ZatelServerAddr,
+    verbose: bool,
+) {
+    let mut connection = connect(server_addr).await.unwrap();
+    match ipc_exec(
+        &mut connection,
+        &ZatelIpcMessage::new(ZatelIpcData::DeactivateConf(uuid.to_string())),
+    )
+    .await
+    {
+        Ok(r) => {
+            print_plugin_logs(verbose, &r.log);
+            if let ZatelIpcData::DeactivateConfReply(ztl_con) = &r.data {
+                println!("Connection deactivated:");
                 print_connection(&ztl_con);
                 println!("");
             } else {
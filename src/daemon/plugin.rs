@@ -12,46 +12,173 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::os::unix::fs::PermissionsExt;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use tokio::net::UnixStream;
+use ed25519_dalek::PublicKey;
+use interprocess::local_socket::{tokio::LocalSocketStream, GenericNamespaced};
+use log::{debug, error, warn};
+use tokio::sync::Mutex;
+use tokio::time::{sleep, timeout, Instant};
 use zatel::{
-    ipc_connect_with_path, ipc_exec, ZatelError, ZatelIpcData, ZatelIpcMessage,
+    daemon_identity, enroll_bootstrapped_plugin_key, ipc_connect_with_path,
+    ipc_exec, ZatelError, ZatelIpcData, ZatelIpcMessage, ZatelIpcStream,
     ZatelPluginInfo,
 };
 
 const PLUGIN_PREFIX: &str = "zatel_plugin_";
-const PLUGIN_SOCKET_PREFIX: &str = "/tmp/zatel_plugin_";
+// Used only as the fallback socket folder on platforms without namespaced
+// local sockets; created with restrictive permissions by the packaging/
+// init scripts, same as /tmp/zatel_socket is today.
+const PLUGIN_SOCKET_FALLBACK_DIR: &str = "/run/zatel";
 
-const PLUGIN_CONNECT_REPLY_COUNT: usize = 10;
-const PLUGIN_CONNECT_REPLY_INTERVAL: u64 = 100; // 100ms
+// Backoff schedule for connecting to a just-spawned plugin's socket before
+// it has started listening: start small since most plugins are ready
+// almost immediately, double on each failure, and give up once the total
+// wait crosses PLUGIN_CONNECT_TIMEOUT rather than after a fixed attempt
+// count.
+const PLUGIN_CONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const PLUGIN_CONNECT_MAX_BACKOFF: Duration = Duration::from_millis(500);
+const PLUGIN_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// How long a removed plugin's process is given to exit on its own after
+// SIGTERM before it is sent SIGKILL instead.
+const PLUGIN_TERMINATE_GRACE_PERIOD: Duration = Duration::from_secs(5);
+const PLUGIN_TERMINATE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+// Tracks the running Child handle of every plugin process this daemon has
+// spawned, keyed by plugin name, so a later Reload can tell which plugins
+// are still alive and send SIGTERM/SIGKILL to the ones that disappeared
+// from the search folder. Kept separate from PluginRegistry (the
+// ZatelPluginInfo list handed to every request) since Child is neither
+// Clone nor meaningful outside of daemon::plugin.
+pub(crate) type PluginProcesses =
+    Arc<Mutex<HashMap<String, std::process::Child>>>;
 
 // Each plugin will be invoked in a thread with a socket path string as its
 // first argument. The plugin should listen on that socket and wait command
 // from plugin.
 //
-pub(crate) async fn load_plugins() -> Vec<ZatelPluginInfo> {
-    eprintln!("DEBUG: Loading plugins");
-    let mut plugins = Vec::new();
+// Starting up has no previous plugin set to diff against, so it is just a
+// Reload from an empty registry: everything found on disk counts as newly
+// added.
+pub(crate) async fn load_plugins() -> (Vec<ZatelPluginInfo>, PluginProcesses) {
+    debug!("Loading plugins");
+    let processes: PluginProcesses = Arc::new(Mutex::new(HashMap::new()));
+    let (plugins, _added, _removed, failed) =
+        reload_plugins(&[], &processes).await;
+    if !failed.is_empty() {
+        error!("Failed to start plugin(s): {:?}", failed);
+    }
+    (plugins, processes)
+}
+
+// Re-scan the plugin search folder and reconcile the running set against
+// it: a plugin already running and still present on disk is left alone
+// (no respawn, no dropped connections against it), a newly appeared
+// plugin is started, and a plugin that disappeared from disk is asked to
+// terminate gracefully. Returns the new plugin list plus the names added,
+// removed and failed, so the caller can both swap the shared registry and
+// report what changed.
+pub(crate) async fn reload_plugins(
+    current: &[ZatelPluginInfo],
+    processes: &PluginProcesses,
+) -> (Vec<ZatelPluginInfo>, Vec<String>, Vec<String>, Vec<String>) {
+    debug!("Reloading plugins");
+    let current_by_name: HashMap<&str, &ZatelPluginInfo> =
+        current.iter().map(|p| (p.name.as_str(), p)).collect();
+
+    let discovered = discover_plugin_execs();
+    let discovered_names: HashSet<&str> =
+        discovered.iter().map(|(_, name)| name.as_str()).collect();
+
+    let mut new_plugins = Vec::new();
+    let mut added = Vec::new();
+    let mut failed = Vec::new();
+
+    for (plugin_exec_path, plugin_name) in &discovered {
+        if let Some(existing) = current_by_name.get(plugin_name.as_str()) {
+            // Still on disk and still running: leave its process and
+            // socket alone.
+            new_plugins.push((*existing).clone());
+            continue;
+        }
+        debug!("Found new plugin {}", plugin_exec_path);
+        match plugin_start(plugin_exec_path, plugin_name).await {
+            Ok((plugin, child)) => {
+                debug!(
+                    "Plugin {} started at {} with capacities: {:?}",
+                    &plugin.name, &plugin.socket_path, &plugin.capacities
+                );
+                // Only held long enough to record the handle: starting
+                // the next plugin (or a concurrent reload's own inserts)
+                // should not have to wait on this one.
+                processes.lock().await.insert(plugin_name.clone(), child);
+                added.push(plugin_name.clone());
+                new_plugins.push(plugin);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to start plugin {}: {}",
+                    plugin_exec_path, e
+                );
+                failed.push(plugin_name.clone());
+            }
+        }
+    }
+
+    // Collect the handles to terminate while holding the lock only long
+    // enough to remove them from the map, then terminate each one (which
+    // may wait up to PLUGIN_TERMINATE_GRACE_PERIOD) without the lock
+    // held, so a concurrent reload is never blocked on it.
+    let mut removed = Vec::new();
+    let mut to_terminate = Vec::new();
+    {
+        let mut processes = processes.lock().await;
+        for name in current_by_name.keys() {
+            if discovered_names.contains(name) {
+                continue;
+            }
+            removed.push(name.to_string());
+            if let Some(child) = processes.remove(*name) {
+                to_terminate.push((name.to_string(), child));
+            }
+        }
+    }
+    for (name, child) in to_terminate {
+        terminate_plugin_process(&name, child).await;
+    }
+
+    (new_plugins, added, removed, failed)
+}
+
+// Find every plugin executable in the search folder, returning each one's
+// full path alongside the name it will be registered under.
+fn discover_plugin_execs() -> Vec<(String, String)> {
+    let mut found = Vec::new();
     let search_folder = match std::env::var("ZATEL_PLUGIN_FOLDER") {
         Ok(d) => d,
         Err(_) => get_current_exec_folder(),
     };
-    eprintln!("DEBUG: Searching plugin at {}", search_folder);
+    debug!("Searching plugin at {}", search_folder);
     match std::fs::read_dir(&search_folder) {
         Ok(dir) => {
             for entry in dir {
                 let file_name = match entry {
                     Ok(f) => f.file_name(),
                     Err(e) => {
-                        eprintln!("FAIL: Failed to read dir entry: {}", e);
+                        error!("Failed to read dir entry: {}", e);
                         continue;
                     }
                 };
                 let file_name = match file_name.to_str() {
                     Some(n) => n,
                     None => {
-                        eprintln!("BUG: Failed to read file_name",);
+                        error!("Failed to read file_name");
                         continue;
                     }
                 };
@@ -65,60 +192,140 @@ pub(crate) async fn load_plugins() -> Vec<ZatelPluginInfo> {
                         match file_name.strip_prefix(PLUGIN_PREFIX) {
                             Some(n) => n,
                             None => {
-                                eprintln!(
-                                    "BUG: file_name {} not started with {}",
+                                error!(
+                                    "file_name {} not started with {}",
                                     file_name, PLUGIN_PREFIX,
                                 );
                                 continue;
                             }
                         };
-                    println!("DEBUG: Found plugin {}", &plugin_exec_path);
-                    match plugin_start(&plugin_exec_path, &plugin_name).await {
-                        Ok(plugin) => {
-                            eprintln!(
-                                "DEBUG: Plugin {} started at {} with \
-                                capacities: {:?}",
-                                &plugin.name,
-                                &plugin.socket_path,
-                                &plugin.capacities
-                            );
-                            plugins.push(plugin);
-                        }
-                        Err(e) => {
-                            eprintln!(
-                                "ERROR: Failed to start plugin {}: {}",
-                                &plugin_exec_path, e
-                            );
-                            continue;
-                        }
-                    }
+                    found.push((plugin_exec_path, plugin_name.to_string()));
                 }
             }
         }
         Err(e) => {
-            eprintln!("Faild to open plugin search dir /usr/bin: {}", e);
+            error!("Faild to open plugin search dir /usr/bin: {}", e);
         }
     };
-    plugins
+    found
+}
+
+// Ask a removed plugin's process to shut down: SIGTERM first, then
+// SIGKILL if it has not exited by the time PLUGIN_TERMINATE_GRACE_PERIOD
+// elapses, mirroring the grace-period-then-abandon pattern the daemon
+// itself uses for its own client tasks on shutdown.
+async fn terminate_plugin_process(
+    name: &str,
+    mut child: std::process::Child,
+) {
+    let pid = child.id() as libc::pid_t;
+    // SAFETY: `pid` is this daemon's own direct child, still held via
+    // `child`, so the pid cannot have been recycled to an unrelated
+    // process out from under us.
+    unsafe {
+        libc::kill(pid, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + PLUGIN_TERMINATE_GRACE_PERIOD;
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => return,
+            Ok(None) => {}
+            Err(e) => {
+                warn!(
+                    "plugin {}: failed to poll exit status: {}",
+                    name, e
+                );
+                return;
+            }
+        }
+        if Instant::now() >= deadline {
+            warn!(
+                "plugin {}: did not exit within {:?} of SIGTERM, sending \
+                SIGKILL",
+                name, PLUGIN_TERMINATE_GRACE_PERIOD
+            );
+            let _ = child.kill();
+            let _ = child.wait();
+            return;
+        }
+        sleep(PLUGIN_TERMINATE_POLL_INTERVAL).await;
+    }
+}
+
+// Derive a short, collision-safe socket name for a plugin instance. The
+// name is not predictable ahead of time (it mixes in the pid and the
+// current time), so two daemons racing to start the same plugin, or a
+// daemon restarting while a stale plugin is still shutting down, cannot
+// collide on the same socket.
+fn gen_plugin_socket_name(plugin_exec_path: &str) -> String {
+    let mut hasher = DefaultHasher::new();
+    plugin_exec_path.hash(&mut hasher);
+    std::process::id().hash(&mut hasher);
+    if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        d.as_nanos().hash(&mut hasher);
+    }
+    let hex = format!("{:016x}", hasher.finish());
+
+    if GenericNamespaced::is_supported() {
+        format!("zatel-{}", hex)
+    } else {
+        format!("{}/{}.sock", PLUGIN_SOCKET_FALLBACK_DIR, hex)
+    }
 }
 
 async fn plugin_start(
     plugin_exec_path: &str,
-    plugin_name: &str,
-) -> Result<ZatelPluginInfo, ZatelError> {
-    let socket_path = format!("{}{}", PLUGIN_SOCKET_PREFIX, plugin_name);
+    _plugin_name: &str,
+) -> Result<(ZatelPluginInfo, std::process::Child), ZatelError> {
+    let socket_path = gen_plugin_socket_name(plugin_exec_path);
+    let daemon_pubkey = daemon_identity()?.public.to_bytes();
+    // Handed to the plugin as a spawn-time argument rather than asserted
+    // over the socket later, so the plugin can trust it: see
+    // plugin::challenge_client, which every plugin uses to authenticate
+    // the daemon client back, closing the direction the daemon's own
+    // authenticate_plugin handshake does not cover.
+    let daemon_pubkey_hex = hex::encode(daemon_pubkey);
     // Invoke the plugin in child.
     match std::process::Command::new(plugin_exec_path)
         .arg(&socket_path)
+        .arg(&daemon_pubkey_hex)
         .spawn()
     {
-        Ok(_) => {
-            println!(
-                "DEBUG: Plugin {} started at {}",
+        Ok(mut child) => {
+            debug!(
+                "Plugin {} started at {}",
                 plugin_exec_path, &socket_path
             );
 
-            query_plugin_info(&socket_path).await
+            match query_plugin_info(&socket_path).await {
+                Ok(info) => {
+                    // Trust the identity this plugin reported on its own
+                    // freshly spawned socket, so a daemon with no
+                    // /etc/zatel/authorized_plugin_keys file yet still
+                    // authenticates the plugins it launched itself
+                    // rather than rejecting every request to them.
+                    match PublicKey::from_bytes(&info.pubkey) {
+                        Ok(pubkey) => enroll_bootstrapped_plugin_key(pubkey),
+                        Err(e) => warn!(
+                            "Plugin {} reported an invalid ed25519 pubkey, \
+                            not enrolling it into the authorized-keys \
+                            allow-list: {}",
+                            plugin_exec_path, e
+                        ),
+                    }
+                    Ok((info, child))
+                }
+                // The process never came up as a usable plugin: kill and
+                // reap it here rather than handing back an error with no
+                // handle, which would otherwise leak an orphaned,
+                // untracked process that a later reload keeps retrying.
+                Err(e) => {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                    Err(e)
+                }
+            }
         }
         Err(e) => Err(ZatelError::plugin_error(format!(
             "Failed to start plugin {} {}: {}",
@@ -159,6 +366,7 @@ async fn query_plugin_info(
 
     if let ZatelIpcMessage {
         data: ZatelIpcData::QueryPluginInfoReply(mut plugin_info),
+        request_id: _,
         log: _,
     } = ipc_msg
     {
@@ -172,33 +380,43 @@ async fn query_plugin_info(
     }
 }
 
+// A freshly spawned plugin has not necessarily started listening on its
+// socket yet, so connecting is retried with exponential backoff (never
+// blocking the executor thread) until either the connect succeeds or
+// PLUGIN_CONNECT_TIMEOUT elapses, at which point a typed timeout error is
+// returned so callers can tell "plugin never came up" apart from
+// `query_plugin_info`'s own "plugin replied with garbage" error.
 async fn ipc_connect_with_retry(
     socket_path: &str,
-) -> Result<UnixStream, ZatelError> {
-    for i in 0..PLUGIN_CONNECT_REPLY_COUNT {
-        std::thread::sleep(std::time::Duration::from_millis(
-            PLUGIN_CONNECT_REPLY_INTERVAL,
-        ));
+) -> Result<ZatelIpcStream<LocalSocketStream>, ZatelError> {
+    match timeout(PLUGIN_CONNECT_TIMEOUT, connect_with_backoff(socket_path))
+        .await
+    {
+        Ok(result) => result,
+        Err(_) => Err(ZatelError::timeout(format!(
+            "Plugin IPC socket {} did not come up within {:?}",
+            socket_path, PLUGIN_CONNECT_TIMEOUT
+        ))),
+    }
+}
+
+async fn connect_with_backoff(
+    socket_path: &str,
+) -> Result<ZatelIpcStream<LocalSocketStream>, ZatelError> {
+    let mut backoff = PLUGIN_CONNECT_INITIAL_BACKOFF;
+    loop {
         match ipc_connect_with_path(socket_path).await {
+            Ok(s) => return Ok(s),
             Err(e) => {
-                if i == PLUGIN_CONNECT_REPLY_COUNT - 1 {
-                    return Err(ZatelError::plugin_error(format!(
-                        "Failed to connect plugin IPC socket {}: {}",
-                        socket_path, e
-                    )));
-                } else {
-                    eprintln!(
-                        "DEBUG: Failed to connect plugin \
-                        socket_path: {}: {}, retrying",
-                        socket_path, e
-                    );
-                    continue;
-                }
+                warn!(
+                    "Failed to connect plugin socket_path: {}: {}, \
+                    retrying in {:?}",
+                    socket_path, e, backoff
+                );
+                sleep(backoff).await;
+                backoff =
+                    std::cmp::min(backoff * 2, PLUGIN_CONNECT_MAX_BACKOFF);
             }
-            Ok(s) => return Ok(s),
         }
     }
-    Err(ZatelError::bug(
-        "This should never happen in ipc_connect_with_retry".into(),
-    ))
 }
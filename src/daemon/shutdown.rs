@@ -0,0 +1,100 @@
+//    Copyright 2021 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::future::Future;
+use std::time::Duration;
+
+use log::{debug, warn};
+use tokio::{sync::watch, task::JoinSet, time::timeout};
+
+// Tracks every spawned per-client task so main() can stop accepting new
+// connections on SIGTERM/SIGINT and then wait for the in-flight ones to
+// wind down on their own before giving up on them.
+pub(crate) struct ShutdownHandle {
+    shutdown_tx: watch::Sender<bool>,
+    tasks: JoinSet<()>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new() -> Self {
+        let (shutdown_tx, _) = watch::channel(false);
+        ShutdownHandle {
+            shutdown_tx,
+            tasks: JoinSet::new(),
+        }
+    }
+
+    // Each handle_client task holds its own Receiver so it can `select!`
+    // on it every loop iteration; a watch channel (rather than a Notify)
+    // guarantees a receiver that subscribed before shutdown was signalled
+    // still observes it even if it was not actively polling at that exact
+    // moment.
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.shutdown_tx.subscribe()
+    }
+
+    // Mirrors the add_service(name, srv) pattern this was modelled on:
+    // hand over a spawned task under a human name, logged when it exits.
+    pub(crate) fn add_service<F>(&mut self, name: &str, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        // Reap whatever already finished before tracking one more: tasks
+        // only get awaited in bulk in `shutdown()`, so without this a
+        // long-lived daemon serving many short-lived clients would keep
+        // every completed task's JoinSet entry around until it exits.
+        while let Some(result) = self.tasks.try_join_next() {
+            log_join_result(result);
+        }
+
+        let name = name.to_string();
+        self.tasks.spawn(async move {
+            task.await;
+            debug!("daemon: service {} exited", name);
+        });
+    }
+
+    // Tell every subscriber to stop, then wait up to `grace_period` for
+    // all tracked tasks to finish cleanly. Tasks still running past the
+    // grace period are abandoned rather than awaited forever.
+    pub(crate) async fn shutdown(mut self, grace_period: Duration) {
+        debug!(
+            "daemon: shutting down, {} task(s) still tracked",
+            self.tasks.len()
+        );
+        // Nothing to do if every subscriber already dropped its receiver.
+        let _ = self.shutdown_tx.send(true);
+
+        if timeout(grace_period, async {
+            while let Some(result) = self.tasks.join_next().await {
+                log_join_result(result);
+            }
+        })
+        .await
+        .is_err()
+        {
+            warn!(
+                "daemon: {} task(s) did not exit within {:?}, abandoning them",
+                self.tasks.len(),
+                grace_period
+            );
+        }
+    }
+}
+
+fn log_join_result(result: Result<(), tokio::task::JoinError>) {
+    if let Err(e) = result {
+        warn!("daemon: a client task panicked: {}", e);
+    }
+}
@@ -13,103 +13,382 @@
 // limitations under the License.
 
 mod plugin;
+mod reconcile;
+mod shutdown;
 
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
+use log::{debug, error, info, warn};
 use serde_yaml;
-use tokio::{self, io::AsyncWriteExt, net::UnixStream, task};
+use tokio::{
+    self,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    signal::unix::{signal, SignalKind},
+    sync::{watch, RwLock, Semaphore},
+    time::timeout,
+};
 use uuid::Uuid;
 use zatel::{
-    ipc_bind, ipc_plugins_exec, ipc_recv_safe, ipc_send, merge_yaml_mappings,
-    ZatelConnection, ZatelError, ZatelIpcData, ZatelIpcMessage,
-    ZatelPluginCapacity, ZatelPluginInfo,
+    bind_transport, duration_from_env, init_logging, ipc_accept,
+    ipc_plugins_exec, ipc_recv_safe, ipc_send, merge_yaml_mappings,
+    merge_yaml_mappings_recursive, server_addr_from_env, unbind_transport,
+    yaml_diff, ZatelConnection, ZatelError, ZatelIpcData, ZatelIpcMessage,
+    ZatelIpcStream, ZatelLogEntry, ZatelPluginCapacity, ZatelPluginInfo,
+    ZATEL_IPC_TIMEOUT, ZATEL_MAX_CONCURRENT_CONNECTIONS,
 };
 
-use crate::plugin::load_plugins;
+use crate::plugin::{load_plugins, reload_plugins, PluginProcesses};
+use crate::shutdown::ShutdownHandle;
+
+// Holds the live plugin registry so Reload can swap it in place: a read
+// lock is only ever held long enough to clone a snapshot for a single
+// connection, so in-flight requests keep running against the snapshot
+// they started with even after a Reload commits a new one.
+type PluginRegistry = Arc<RwLock<Vec<ZatelPluginInfo>>>;
+
+// How long a graceful shutdown waits for in-flight client connections to
+// finish on their own before abandoning them.
+const ZATEL_SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+// Overrides how long a client connection (ztl, or any other IPC caller)
+// may sit idle between requests before the daemon closes it, accepting
+// the same compact duration strings as parse_duration(). Falls back to
+// ZATEL_IPC_TIMEOUT when unset or invalid.
+const ZATEL_CLIENT_IDLE_TIMEOUT_ENV: &str = "ZATEL_CLIENT_IDLE_TIMEOUT";
 
 #[tokio::main(flavor = "multi_thread", worker_threads = 50)]
 async fn main() {
-    let listener = match ipc_bind() {
+    if let Err(e) = init_logging("ztld") {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let server_addr = server_addr_from_env();
+    let listener = match bind_transport(&server_addr).await {
         Ok(l) => l,
         Err(e) => {
-            eprintln!("{}", e);
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let (initial_plugins, plugin_processes) = load_plugins().await;
+    let plugins: PluginRegistry = Arc::new(RwLock::new(initial_plugins));
+    let client_idle_timeout =
+        duration_from_env(ZATEL_CLIENT_IDLE_TIMEOUT_ENV, ZATEL_IPC_TIMEOUT);
+
+    let connection_limiter =
+        Arc::new(Semaphore::new(ZATEL_MAX_CONCURRENT_CONNECTIONS));
+
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("daemon: failed to install SIGTERM handler: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut sigint = match signal(SignalKind::interrupt()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("daemon: failed to install SIGINT handler: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // SIGHUP is the traditional "reload your config" signal, so an
+    // operator (or a package init script) can refresh the plugin set
+    // in place without restarting the whole daemon and dropping every
+    // client connection in flight.
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("daemon: failed to install SIGHUP handler: {}", e);
             std::process::exit(1);
         }
     };
 
-    // We don't plan to unload plugin during runtime when plugin is slow or bad.
-    // To support that, we need a mutex protected Vec which is complex.
-    // We assume the plugin is trustable.
-    let plugins = load_plugins().await;
+    let mut shutdown_handle = ShutdownHandle::new();
 
     loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                eprintln!("DEBUG: daemon: IPC client connected");
-                // TODO: Limit the maximum connected client.
-                let plugins_clone = plugins.clone();
-                task::spawn(async move {
-                    handle_client(stream, &plugins_clone).await
-                });
+        tokio::select! {
+            accepted = listener.accept() => {
+                match accepted {
+                    Ok(stream) => {
+                        debug!("daemon: IPC client connected");
+                        // Wait for a free permit without letting that
+                        // block the daemon from reacting to a shutdown
+                        // signal: a daemon already at
+                        // ZATEL_MAX_CONCURRENT_CONNECTIONS only frees a
+                        // permit when some other client disconnects or
+                        // times out, which could otherwise hide a pending
+                        // SIGTERM/SIGINT for a long time.
+                        let permit = tokio::select! {
+                            biased;
+
+                            _ = sigterm.recv() => {
+                                info!(
+                                    "daemon: received SIGTERM, shutting down"
+                                );
+                                break;
+                            }
+                            _ = sigint.recv() => {
+                                info!(
+                                    "daemon: received SIGINT, shutting down"
+                                );
+                                break;
+                            }
+                            p = connection_limiter.clone().acquire_owned() => p,
+                        };
+                        let permit = match permit {
+                            Ok(p) => p,
+                            Err(e) => {
+                                error!(
+                                    "daemon: connection limiter semaphore \
+                                    closed: {}",
+                                    e
+                                );
+                                continue;
+                            }
+                        };
+                        let plugins_snapshot = plugins.read().await.clone();
+                        let plugins = plugins.clone();
+                        let plugin_processes = plugin_processes.clone();
+                        let shutdown_rx = shutdown_handle.subscribe();
+                        shutdown_handle.add_service("ipc-client", async move {
+                            let _permit = permit;
+                            match ipc_accept(stream).await {
+                                Ok(ipc_stream) => {
+                                    handle_client(
+                                        ipc_stream,
+                                        plugins_snapshot,
+                                        &plugins,
+                                        &plugin_processes,
+                                        client_idle_timeout,
+                                        shutdown_rx,
+                                    )
+                                    .await
+                                }
+                                Err(e) => {
+                                    error!(
+                                        "daemon: failed to negotiate wire \
+                                        format with client: {}",
+                                        e
+                                    );
+                                }
+                            }
+                        });
+                    }
+                    Err(e) => {
+                        error!("{}", e);
+                    }
+                }
             }
-            Err(e) => {
-                eprintln!("{}", e);
+            _ = sigterm.recv() => {
+                info!("daemon: received SIGTERM, shutting down");
+                break;
+            }
+            _ = sigint.recv() => {
+                info!("daemon: received SIGINT, shutting down");
+                break;
+            }
+            _ = sighup.recv() => {
+                info!("daemon: received SIGHUP, reloading plugins");
+                // A reload can take several seconds per plugin added or
+                // removed, so it is tracked like any other service rather
+                // than awaited inline here, which would otherwise stall
+                // accepting new connections and noticing a later signal
+                // for as long as the reload is in progress.
+                let plugins = plugins.clone();
+                let plugin_processes = plugin_processes.clone();
+                shutdown_handle.add_service("sighup-reload", async move {
+                    let current = plugins.read().await.clone();
+                    match handle_reload(
+                        &current,
+                        &plugins,
+                        &plugin_processes,
+                    )
+                    .await
+                    {
+                        Ok(ZatelIpcMessage {
+                            data:
+                                ZatelIpcData::ReloadPluginsReply {
+                                    added,
+                                    removed,
+                                    failed,
+                                },
+                            ..
+                        }) => {
+                            info!(
+                                "daemon: reload complete: added={:?} \
+                                removed={:?} failed={:?}",
+                                added, removed, failed
+                            );
+                        }
+                        // handle_reload only ever returns Err on a bug,
+                        // and only ever returns Ok with
+                        // ReloadPluginsReply.
+                        Ok(reply) => {
+                            error!(
+                                "daemon: unexpected reload reply: {:?}",
+                                reply
+                            );
+                        }
+                        Err(e) => error!("daemon: reload failed: {}", e),
+                    }
+                });
             }
         }
     }
+
+    // A second signal during the grace period means the operator wants out
+    // now, rather than waiting for in-flight clients to wind down.
+    tokio::select! {
+        _ = shutdown_handle.shutdown(ZATEL_SHUTDOWN_GRACE_PERIOD) => {}
+        _ = sigterm.recv() => {
+            warn!(
+                "daemon: received second SIGTERM during shutdown, exiting \
+                immediately"
+            );
+        }
+        _ = sigint.recv() => {
+            warn!(
+                "daemon: received second SIGINT during shutdown, exiting \
+                immediately"
+            );
+        }
+    }
+    unbind_transport(&server_addr);
 }
 
-async fn shutdown_connection(stream: &mut UnixStream) {
-    if let Err(e) = stream.shutdown().await {
-        eprintln!("ERROR: Daemon: failed to shutdown a connection: {}", e);
+async fn shutdown_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    ipc_stream: &mut ZatelIpcStream<S>,
+) {
+    if let Err(e) = ipc_stream.stream.shutdown().await {
+        error!("daemon: failed to shutdown a connection: {}", e);
     }
 }
 
-// TODO: Implement on:
-//  * timeout
-async fn handle_client(mut stream: UnixStream, plugins: &[ZatelPluginInfo]) {
+async fn handle_client<S: AsyncRead + AsyncWrite + Unpin>(
+    mut ipc_stream: ZatelIpcStream<S>,
+    mut plugins: Vec<ZatelPluginInfo>,
+    registry: &PluginRegistry,
+    processes: &PluginProcesses,
+    idle_timeout: Duration,
+    mut shutdown_rx: watch::Receiver<bool>,
+) {
     loop {
-        match ipc_recv_safe(&mut stream).await {
-            Ok(ipc_msg) => {
-                let reply_ipc_msg =
-                    ZatelIpcMessage::from_result(match ipc_msg.data {
-                        ZatelIpcData::ConnectionClosed => {
-                            shutdown_connection(&mut stream).await;
-                            break;
-                        }
-                        ZatelIpcData::QueryIfaceInfo(filter) => {
-                            handle_query(&filter, plugins).await
-                        }
-                        ZatelIpcData::SaveConf(connection) => {
-                            handle_save_conf(&connection, plugins).await
-                        }
-                        ZatelIpcData::QuerySavedConf(uuid) => {
-                            handle_query_saved_conf(&uuid, plugins).await
-                        }
-                        ZatelIpcData::QuerySavedConfAll => {
-                            handle_query_saved_conf_all(plugins).await
-                        }
-                        _ => {
-                            eprintln!(
-                                "ERROR: got unknown IPC message: {:?}",
-                                &ipc_msg
-                            );
-                            Ok(ZatelIpcMessage::new(ZatelIpcData::Error(
-                                ZatelError::invalid_argument(format!(
-                                    "Invalid IPC message: {:?}",
+        // Biased so a pending shutdown is always noticed ahead of picking
+        // up one more request, once the client's current request (if any)
+        // has already been replied to.
+        tokio::select! {
+            biased;
+
+            _ = shutdown_rx.changed() => {
+                debug!("daemon: notifying client of shutdown");
+                let _ = ipc_send(
+                    &mut ipc_stream,
+                    &ZatelIpcMessage::new(ZatelIpcData::ConnectionClosed),
+                )
+                .await;
+                shutdown_connection(&mut ipc_stream).await;
+                break;
+            }
+
+            result = timeout(
+                idle_timeout,
+                ipc_recv_safe(&mut ipc_stream),
+            ) => match result {
+                Ok(Ok(ipc_msg)) => {
+                    let request_id = ipc_msg.request_id;
+                    let mut reply_ipc_msg =
+                        ZatelIpcMessage::from_result(match ipc_msg.data {
+                            ZatelIpcData::ConnectionClosed => {
+                                shutdown_connection(&mut ipc_stream).await;
+                                break;
+                            }
+                            ZatelIpcData::QueryIfaceInfo(filter) => {
+                                handle_query(&filter, &plugins).await
+                            }
+                            ZatelIpcData::SaveConf(connection) => {
+                                reconcile::reconcile_save_conf(
+                                    &connection,
+                                    &plugins,
+                                )
+                                .await
+                            }
+                            ZatelIpcData::QuerySavedConf(uuid) => {
+                                handle_query_saved_conf(&uuid, &plugins).await
+                            }
+                            ZatelIpcData::QuerySavedConfAll => {
+                                handle_query_saved_conf_all(&plugins).await
+                            }
+                            ZatelIpcData::DeleteConf(uuid) => {
+                                handle_delete_conf(&uuid, &plugins).await
+                            }
+                            ZatelIpcData::ActivateConf(uuid) => {
+                                handle_activate_conf(&uuid, &plugins).await
+                            }
+                            ZatelIpcData::DeactivateConf(uuid) => {
+                                handle_deactivate_conf(&uuid, &plugins).await
+                            }
+                            ZatelIpcData::QueryFirewallRules => {
+                                handle_query_firewall_rules(&plugins).await
+                            }
+                            ZatelIpcData::ApplyFirewallRules(rules) => {
+                                handle_apply_firewall_rules(&rules, &plugins)
+                                    .await
+                            }
+                            ZatelIpcData::ReloadPlugins => {
+                                let reply = handle_reload(
+                                    &plugins, registry, processes,
+                                )
+                                .await;
+                                if let Ok(ZatelIpcMessage {
+                                    data:
+                                        ZatelIpcData::ReloadPluginsReply {
+                                            ..
+                                        },
+                                    ..
+                                }) = &reply
+                                {
+                                    plugins = registry.read().await.clone();
+                                }
+                                reply
+                            }
+                            _ => {
+                                error!(
+                                    "got unknown IPC message: {:?}",
                                     &ipc_msg
-                                )),
-                            )))
-                        }
-                    });
-                if let Err(e) = ipc_send(&mut stream, &reply_ipc_msg).await {
-                    eprintln!("ERROR: Failed to reply via IPC {}", e);
+                                );
+                                Ok(ZatelIpcMessage::new(ZatelIpcData::Error(
+                                    ZatelError::invalid_argument(format!(
+                                        "Invalid IPC message: {:?}",
+                                        &ipc_msg
+                                    )),
+                                )))
+                            }
+                        });
+                    reply_ipc_msg.request_id = request_id;
+                    if let Err(e) =
+                        ipc_send(&mut ipc_stream, &reply_ipc_msg).await
+                    {
+                        error!("Failed to reply via IPC {}", e);
+                    }
+                }
+                Ok(Err(e)) => {
+                    error!("IPC error {}", e);
+                    shutdown_connection(&mut ipc_stream).await;
+                    break;
+                }
+                Err(_) => {
+                    warn!(
+                        "IPC error: client idle for longer than {:?}",
+                        idle_timeout
+                    );
+                    shutdown_connection(&mut ipc_stream).await;
+                    break;
                 }
-            }
-            Err(e) => {
-                eprintln!("IPC error {}", e);
-                shutdown_connection(&mut stream).await;
-                break;
             }
         }
     }
@@ -119,7 +398,7 @@ async fn handle_query(
     filter: &str,
     plugins: &[ZatelPluginInfo],
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    eprintln!("DEBUG: handle_query {}", filter);
+    debug!("handle_query {}", filter);
     let ipc_msg =
         ZatelIpcMessage::new(ZatelIpcData::QueryIfaceInfo(filter.into()));
 
@@ -127,9 +406,12 @@ async fn handle_query(
         ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Query).await;
     let reply_strs = extract_strs_from_ipc_msg(&reply_ipc_msg);
 
-    Ok(ZatelIpcMessage::new(ZatelIpcData::QueryIfaceInfoReply(
-        merge_yaml_mappings(&reply_strs)?,
-    )))
+    Ok(with_plugin_logs(
+        ZatelIpcMessage::new(ZatelIpcData::QueryIfaceInfoReply(
+            merge_yaml_mappings(&reply_strs)?,
+        )),
+        collect_plugin_logs(&reply_ipc_msg),
+    ))
 }
 
 // Steps:
@@ -142,9 +424,9 @@ async fn handle_save_conf(
     connection: &ZatelConnection,
     plugins: &[ZatelPluginInfo],
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    eprintln!("DEBUG: handle_save_conf {:?}", connection);
+    debug!("handle_save_conf {:?}", connection);
 
-    validate_conf(&connection.config, plugins).await?;
+    let mut log_entries = validate_conf(&connection.config, plugins).await?;
 
     let mut ztl_con = connection.clone();
 
@@ -166,9 +448,10 @@ async fn handle_save_conf(
 
     let reply_ipc_msgs =
         ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Config).await;
+    log_entries.extend(collect_plugin_logs(&reply_ipc_msgs));
 
     let mut reply_ztl_cons = Vec::new();
-    for reply_ipc_msg in reply_ipc_msgs {
+    for (_, reply_ipc_msg) in reply_ipc_msgs {
         if let ZatelIpcData::SaveConfReply(ztl_con) = reply_ipc_msg.data {
             reply_ztl_cons.push(ztl_con);
         }
@@ -179,17 +462,22 @@ async fn handle_save_conf(
         )))
     } else {
         ztl_con.merge_from(&reply_ztl_cons)?;
-        Ok(ZatelIpcMessage::new(ZatelIpcData::SaveConfReply(ztl_con)))
+        Ok(with_plugin_logs(
+            ZatelIpcMessage::new(ZatelIpcData::SaveConfReply(ztl_con)),
+            log_entries,
+        ))
     }
 }
 
 // Each plugin could only cover a portion of the configure, but they should
-// sum up to the full desire config, or else return ZatelError
+// sum up to the full desire config, or else return ZatelError. Returns the
+// plugin log entries collected while validating, so the caller can fold
+// them into whatever reply it eventually sends back to the client.
 async fn validate_conf(
     conf: &str,
     plugins: &[ZatelPluginInfo],
-) -> Result<(), ZatelError> {
-    eprintln!("DEBUG: validate_conf {}", conf);
+) -> Result<Vec<ZatelLogEntry>, ZatelError> {
+    debug!("validate_conf {}", conf);
     let ipc_msg =
         ZatelIpcMessage::new(ZatelIpcData::ValidateConf(conf.to_string()));
 
@@ -206,6 +494,137 @@ async fn validate_conf(
 
     let reply_ipc_msgs =
         ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Apply).await;
+    let log_entries = collect_plugin_logs(&reply_ipc_msgs);
+    let reply_strs = extract_strs_from_ipc_msg(&reply_ipc_msgs);
+    let merged_reply = merge_yaml_mappings_recursive(reply_strs.as_slice())?;
+    let validated_yaml_mapping: serde_yaml::Value =
+        match serde_yaml::from_str(&merged_reply) {
+            Ok(i) => i,
+            Err(e) => {
+                return Err(ZatelError::bug(format!(
+                    "This should never happen: {}",
+                    e
+                )));
+            }
+        };
+
+    if validated_yaml_mapping != desire_yaml_mapping {
+        let diffs = yaml_diff(&desire_yaml_mapping, &validated_yaml_mapping);
+        let msg = if diffs.is_empty() {
+            format!(
+                "Invalid config, validated: {}, desired: {}",
+                &merged_reply, conf
+            )
+        } else {
+            diffs.join("\n")
+        };
+        Err(ZatelError::invalid_argument(msg))
+    } else {
+        Ok(log_entries)
+    }
+}
+
+// Re-scan the plugin directory and reconcile the running set against it,
+// without disturbing `plugins` (the caller's own snapshot) or any other
+// in-flight connection's snapshot. Only commits the new set to `registry`
+// once it has been fully built, so a Reload failure never leaves the
+// shared registry half-updated.
+async fn handle_reload(
+    plugins: &[ZatelPluginInfo],
+    registry: &PluginRegistry,
+    processes: &PluginProcesses,
+) -> Result<ZatelIpcMessage, ZatelError> {
+    debug!("handle_reload");
+
+    let (new_plugins, added, removed, failed) =
+        reload_plugins(plugins, processes).await;
+
+    *registry.write().await = new_plugins;
+
+    Ok(ZatelIpcMessage::new(ZatelIpcData::ReloadPluginsReply {
+        added,
+        removed,
+        failed,
+    }))
+}
+
+async fn handle_query_firewall_rules(
+    plugins: &[ZatelPluginInfo],
+) -> Result<ZatelIpcMessage, ZatelError> {
+    debug!("handle_query_firewall_rules");
+    let ipc_msg = ZatelIpcMessage::new(ZatelIpcData::QueryFirewallRules);
+
+    let reply_ipc_msg =
+        ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Firewall)
+            .await;
+    let reply_strs = extract_strs_from_ipc_msg(&reply_ipc_msg);
+
+    Ok(with_plugin_logs(
+        ZatelIpcMessage::new(ZatelIpcData::QueryFirewallRulesReply(
+            merge_yaml_mappings(&reply_strs)?,
+        )),
+        collect_plugin_logs(&reply_ipc_msg),
+    ))
+}
+
+// Steps:
+//  1. Send rules string to plugin to validate. Raise error if existing
+//     plugins cannot achieve the full desired rule set.
+//  2. Send rules string to plugin to apply.
+async fn handle_apply_firewall_rules(
+    rules: &str,
+    plugins: &[ZatelPluginInfo],
+) -> Result<ZatelIpcMessage, ZatelError> {
+    debug!("handle_apply_firewall_rules {}", rules);
+
+    let mut log_entries = validate_firewall_rules(rules, plugins).await?;
+
+    let ipc_msg = ZatelIpcMessage::new(ZatelIpcData::ApplyFirewallRules(
+        rules.to_string(),
+    ));
+
+    let reply_ipc_msgs =
+        ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Firewall)
+            .await;
+    log_entries.extend(collect_plugin_logs(&reply_ipc_msgs));
+    let reply_strs = extract_strs_from_ipc_msg(&reply_ipc_msgs);
+
+    Ok(with_plugin_logs(
+        ZatelIpcMessage::new(ZatelIpcData::ApplyFirewallRulesReply(
+            merge_yaml_mappings(&reply_strs)?,
+        )),
+        log_entries,
+    ))
+}
+
+// Each plugin could only cover a portion of the rule set, but they should
+// sum up to the full desired rules, or else return ZatelError. Returns the
+// plugin log entries collected while validating, so the caller can fold
+// them into whatever reply it eventually sends back to the client.
+async fn validate_firewall_rules(
+    rules: &str,
+    plugins: &[ZatelPluginInfo],
+) -> Result<Vec<ZatelLogEntry>, ZatelError> {
+    debug!("validate_firewall_rules {}", rules);
+    let ipc_msg = ZatelIpcMessage::new(ZatelIpcData::ValidateFirewallRules(
+        rules.to_string(),
+    ));
+
+    let desire_yaml_mapping: serde_yaml::Value =
+        match serde_yaml::from_str(rules) {
+            Ok(i) => i,
+            Err(e) => {
+                return Err(ZatelError::invalid_argument(format!(
+                    "Invalid format of YAML: {}",
+                    e
+                )));
+            }
+        };
+
+    let reply_ipc_msgs =
+        ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Firewall)
+            .await;
+    let log_entries = collect_plugin_logs(&reply_ipc_msgs);
     let reply_strs = extract_strs_from_ipc_msg(&reply_ipc_msgs);
     let merged_reply = merge_yaml_mappings(reply_strs.as_slice())?;
     let validated_yaml_mapping: serde_yaml::Value =
@@ -222,25 +641,26 @@ async fn validate_conf(
     if validated_yaml_mapping != desire_yaml_mapping {
         // TODO: provide fancy difference to user via error.
         Err(ZatelError::invalid_argument(format!(
-            "Invalid config, validated: {}, desired: {}",
-            &merged_reply, conf
+            "Invalid firewall rules, validated: {}, desired: {}",
+            &merged_reply, rules
         )))
     } else {
-        Ok(())
+        Ok(log_entries)
     }
 }
 
 async fn handle_query_saved_conf_all(
     plugins: &[ZatelPluginInfo],
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    eprintln!("DEBUG: handle_query_saved_conf_all");
+    debug!("handle_query_saved_conf_all");
 
     let ipc_msg = ZatelIpcMessage::new(ZatelIpcData::QuerySavedConfAll);
 
     let reply_ipc_msgs =
         ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Config).await;
+    let log_entries = collect_plugin_logs(&reply_ipc_msgs);
     let mut all_ztl_cons = HashMap::new();
-    for reply_ipc_msg in reply_ipc_msgs {
+    for (plugin_name, reply_ipc_msg) in reply_ipc_msgs {
         if let ZatelIpcData::QuerySavedConfAllReply(ztl_cons) =
             reply_ipc_msg.data
         {
@@ -248,10 +668,7 @@ async fn handle_query_saved_conf_all(
                 let uuid = match &ztl_con.uuid {
                     Some(u) => u.to_string(),
                     None => {
-                        eprintln!(
-                            "ERROR: plugin reply with None UUID: {:?}",
-                            ztl_con
-                        );
+                        error!("plugin reply with None UUID: {:?}", ztl_con);
                         continue;
                     }
                 };
@@ -260,15 +677,18 @@ async fn handle_query_saved_conf_all(
                 }
             }
         } else {
-            eprintln!(
-                "ERROR: Invalid plugin reply for QuerySavedConfAll: {:?}",
-                reply_ipc_msg
+            error!(
+                "Invalid plugin reply for QuerySavedConfAll from {}: {:?}",
+                plugin_name, reply_ipc_msg
             );
         }
     }
-    Ok(ZatelIpcMessage::new(ZatelIpcData::QuerySavedConfAllReply(
-        all_ztl_cons.iter().map(|(_, v)| v.clone()).collect(),
-    )))
+    Ok(with_plugin_logs(
+        ZatelIpcMessage::new(ZatelIpcData::QuerySavedConfAllReply(
+            all_ztl_cons.iter().map(|(_, v)| v.clone()).collect(),
+        )),
+        log_entries,
+    ))
 }
 
 fn gen_connection_name(config: &str) -> String {
@@ -285,31 +705,65 @@ fn gen_connection_name(config: &str) -> String {
 }
 
 fn extract_strs_from_ipc_msg<'a>(
-    ipc_msgs: &'a [ZatelIpcMessage],
+    ipc_msgs: &'a [(String, ZatelIpcMessage)],
 ) -> Vec<&'a str> {
     let mut data_strs = Vec::new();
-    for ipc_msg in ipc_msgs {
-        if let Ok(s) = ipc_msg.get_data_str() {
-            data_strs.push(s)
+    for (plugin_name, ipc_msg) in ipc_msgs {
+        match ipc_msg.get_data_str() {
+            Ok(s) => data_strs.push(s),
+            Err(_) => warn!(
+                "got invalid reply from plugin {}: {:?}",
+                plugin_name, ipc_msg
+            ),
         }
     }
     data_strs
 }
 
+// Pull every plugin's log entries (already prefixed with the plugin's
+// name by ipc_plugins_exec) off a set of plugin replies, so the final
+// reply the daemon sends back to the client can carry them too and `ztl
+// -v` can show which plugin logged what during this request.
+fn collect_plugin_logs(
+    ipc_msgs: &[(String, ZatelIpcMessage)],
+) -> Vec<ZatelLogEntry> {
+    let mut entries = Vec::new();
+    for (_, ipc_msg) in ipc_msgs {
+        if let Some(log) = &ipc_msg.log {
+            entries.extend(log.iter().cloned());
+        }
+    }
+    entries
+}
+
+// Attach `log_entries` to `reply` when there are any, leaving the reply
+// untouched (and the wire payload smaller) when no plugin logged anything
+// for this request.
+fn with_plugin_logs(
+    mut reply: ZatelIpcMessage,
+    log_entries: Vec<ZatelLogEntry>,
+) -> ZatelIpcMessage {
+    if !log_entries.is_empty() {
+        reply.log = Some(log_entries);
+    }
+    reply
+}
+
 async fn handle_query_saved_conf(
     uuid: &str,
     plugins: &[ZatelPluginInfo],
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    eprintln!("DEBUG: handle_query_saved_conf: {}", uuid);
+    debug!("handle_query_saved_conf: {}", uuid);
 
     let ipc_msg =
         ZatelIpcMessage::new(ZatelIpcData::QuerySavedConf(uuid.to_string()));
 
     let reply_ipc_msgs =
         ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Config).await;
+    let log_entries = collect_plugin_logs(&reply_ipc_msgs);
 
     let mut reply_ztl_cons = Vec::new();
-    for reply_ip_msg in reply_ipc_msgs {
+    for (_, reply_ip_msg) in reply_ipc_msgs {
         if let ZatelIpcData::QuerySavedConfReply(ztl_con) = reply_ip_msg.data {
             reply_ztl_cons.push(ztl_con)
         }
@@ -322,8 +776,135 @@ async fn handle_query_saved_conf(
     } else {
         let mut ztl_con = reply_ztl_cons[0].clone();
         ztl_con.merge_from(&reply_ztl_cons)?;
-        Ok(ZatelIpcMessage::new(ZatelIpcData::QuerySavedConfReply(
-            ztl_con,
+        Ok(with_plugin_logs(
+            ZatelIpcMessage::new(ZatelIpcData::QuerySavedConfReply(ztl_con)),
+            log_entries,
+        ))
+    }
+}
+
+async fn handle_delete_conf(
+    uuid: &str,
+    plugins: &[ZatelPluginInfo],
+) -> Result<ZatelIpcMessage, ZatelError> {
+    debug!("handle_delete_conf: {}", uuid);
+
+    let ipc_msg =
+        ZatelIpcMessage::new(ZatelIpcData::DeleteConf(uuid.to_string()));
+
+    let reply_ipc_msgs =
+        ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Config).await;
+    let log_entries = collect_plugin_logs(&reply_ipc_msgs);
+
+    let mut deleted = false;
+    // A plugin that actually tried to delete the connection and failed
+    // (e.g. "not supported", or a real I/O error) should surface its own
+    // error rather than being papered over with a generic "not found",
+    // which would otherwise look like the uuid never existed.
+    let mut plugin_error = None;
+    for (_, r) in &reply_ipc_msgs {
+        match &r.data {
+            ZatelIpcData::DeleteConfReply(_) => {
+                deleted = true;
+                break;
+            }
+            ZatelIpcData::Error(e) => {
+                if plugin_error.is_none() {
+                    plugin_error = Some(e.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+    if !deleted {
+        Err(plugin_error.unwrap_or_else(|| {
+            ZatelError::invalid_argument(format!(
+                "Connection {} not found",
+                uuid
+            ))
+        }))
+    } else {
+        Ok(with_plugin_logs(
+            ZatelIpcMessage::new(ZatelIpcData::DeleteConfReply(
+                uuid.to_string(),
+            )),
+            log_entries,
+        ))
+    }
+}
+
+// Activate a saved connection: look it up, then feed it through the same
+// self-healing reconcile path SaveConf uses, so `ztl connection up` gets
+// the three-way merge and automatic rollback reconcile_save_conf already
+// provides rather than a second, divergent apply path. Like SaveConf,
+// this only blocks on runtime convergence when an Apply-capacity plugin
+// is registered to actually push the merged config to the runtime.
+async fn handle_activate_conf(
+    uuid: &str,
+    plugins: &[ZatelPluginInfo],
+) -> Result<ZatelIpcMessage, ZatelError> {
+    debug!("handle_activate_conf: {}", uuid);
+
+    let ztl_con = match handle_query_saved_conf(uuid, plugins).await? {
+        ZatelIpcMessage {
+            data: ZatelIpcData::QuerySavedConfReply(ztl_con),
+            ..
+        } => ztl_con,
+        reply => {
+            return Err(ZatelError::bug(format!(
+                "Unexpected reply looking up connection {}: {:?}",
+                uuid, reply
+            )))
+        }
+    };
+
+    match reconcile::reconcile_save_conf(&ztl_con, plugins).await? {
+        ZatelIpcMessage {
+            data: ZatelIpcData::SaveConfReply(ztl_con),
+            log,
+            ..
+        } => Ok(with_plugin_logs(
+            ZatelIpcMessage::new(ZatelIpcData::ActivateConfReply(ztl_con)),
+            log.unwrap_or_default(),
+        )),
+        reply => Err(ZatelError::bug(format!(
+            "Unexpected reply activating connection {}: {:?}",
+            uuid, reply
+        ))),
+    }
+}
+
+async fn handle_deactivate_conf(
+    uuid: &str,
+    plugins: &[ZatelPluginInfo],
+) -> Result<ZatelIpcMessage, ZatelError> {
+    debug!("handle_deactivate_conf: {}", uuid);
+
+    let ipc_msg =
+        ZatelIpcMessage::new(ZatelIpcData::DeactivateConf(uuid.to_string()));
+
+    let reply_ipc_msgs =
+        ipc_plugins_exec(&ipc_msg, plugins, &ZatelPluginCapacity::Config).await;
+    let log_entries = collect_plugin_logs(&reply_ipc_msgs);
+
+    let mut reply_ztl_cons = Vec::new();
+    for (_, reply_ipc_msg) in reply_ipc_msgs {
+        if let ZatelIpcData::DeactivateConfReply(ztl_con) = reply_ipc_msg.data
+        {
+            reply_ztl_cons.push(ztl_con);
+        }
+    }
+    if reply_ztl_cons.len() == 0 {
+        Err(ZatelError::plugin_error(format!(
+            "No plugin has deactivated connection {}",
+            uuid
         )))
+    } else {
+        let mut ztl_con = reply_ztl_cons[0].clone();
+        ztl_con.merge_from(&reply_ztl_cons)?;
+        Ok(with_plugin_logs(
+            ZatelIpcMessage::new(ZatelIpcData::DeactivateConfReply(ztl_con)),
+            log_entries,
+        ))
     }
 }
@@ -0,0 +1,144 @@
+//    Copyright 2021 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use log::warn;
+
+use crate::ZatelError;
+
+// Parse a compact human-readable duration: a bare integer is taken as
+// seconds, otherwise a single trailing unit suffix is required -- "s"
+// (seconds), "m" (minutes) or "h" (hours). Examples: "30s", "5m", "1h",
+// "30".
+pub fn parse_duration(s: &str) -> Result<Duration, ZatelError> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Err(ZatelError::invalid_argument(
+            "Duration string is empty".to_string(),
+        ));
+    }
+
+    let split_at = s
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split_at);
+
+    if digits.is_empty() {
+        return Err(ZatelError::invalid_argument(format!(
+            "Invalid duration {:?}: expected a leading number",
+            s
+        )));
+    }
+    let value: u64 = digits.parse().map_err(|e| {
+        ZatelError::invalid_argument(format!(
+            "Invalid duration {:?}: {}",
+            s, e
+        ))
+    })?;
+
+    let multiplier: u64 = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        _ => {
+            return Err(ZatelError::invalid_argument(format!(
+                "Invalid duration {:?}: unknown unit {:?}, expected one of \
+                s, m, h",
+                s, unit
+            )))
+        }
+    };
+
+    let secs = value.checked_mul(multiplier).ok_or_else(|| {
+        ZatelError::invalid_argument(format!("Duration {:?} is too large", s))
+    })?;
+
+    Ok(Duration::from_secs(secs))
+}
+
+// Read `var_name` as a parse_duration() string, falling back to `default`
+// (and logging a warning) when the variable is unset or fails to parse.
+// Shared by every env-var-overridable timeout so each call site doesn't
+// reimplement the same read/parse/fallback dance.
+pub fn duration_from_env(var_name: &str, default: Duration) -> Duration {
+    match std::env::var(var_name) {
+        Ok(s) => match parse_duration(&s) {
+            Ok(d) => d,
+            Err(e) => {
+                warn!(
+                    "Invalid {}={:?}: {}, falling back to {:?}",
+                    var_name, s, e, default
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_duration_seconds_suffix() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_parse_duration_minutes_suffix() {
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+    }
+
+    #[test]
+    fn test_parse_duration_hours_suffix() {
+        assert_eq!(parse_duration("1h").unwrap(), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_parse_duration_bare_integer_is_seconds() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_parse_duration_trims_whitespace() {
+        assert_eq!(parse_duration(" 30s ").unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_parse_duration_empty_is_error() {
+        assert!(parse_duration("").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_unknown_unit_is_error() {
+        assert!(parse_duration("30d").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_no_leading_number_is_error() {
+        assert!(parse_duration("s").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_garbage_is_error() {
+        assert!(parse_duration("abc").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration_overflow_is_error() {
+        assert!(parse_duration("99999999999999999999h").is_err());
+    }
+}
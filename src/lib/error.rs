@@ -23,6 +23,7 @@ pub enum ErrorKind {
     InvalidArgument,
     ZatelBug,
     PluginError,
+    Timeout,
 }
 
 impl std::fmt::Display for ErrorKind {
@@ -56,6 +57,12 @@ impl ZatelError {
             msg: message,
         }
     }
+    pub fn timeout(message: String) -> ZatelError {
+        ZatelError {
+            kind: ErrorKind::Timeout,
+            msg: message,
+        }
+    }
 }
 
 impl std::fmt::Display for ZatelError {
@@ -13,20 +13,112 @@
 // limitations under the License.
 
 use std::fs::remove_file;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
 
+use bincode;
+use interprocess::local_socket::{
+    tokio::{LocalSocketListener, LocalSocketStream},
+    GenericFilePath, GenericNamespaced, ToFsName, ToNsName,
+};
 use serde::{Deserialize, Serialize};
-use serde_yaml;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{UnixListener, UnixStream};
+use serde_big_array::BigArray;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
 
 use crate::{ZatelConnection, ZatelError, ZatelLogEntry, ZatelPluginInfo};
 
-const DEFAULT_SOCKET_PATH: &str = "/tmp/zatel_socket";
+pub(crate) const DEFAULT_SOCKET_PATH: &str = "/tmp/zatel_socket";
 const IPC_SAFE_SIZE: usize = 1024 * 1024 * 10; // 10 MiB
 
+// Bump this whenever a ZatelIpcData variant is added, removed or its
+// payload shape changes in a way older plugins can't deserialize. This
+// matters more than it used to now that the envelope is bincode (see
+// ipc_send/ipc_recv_get_data): bincode encodes enum variants by index and
+// struct fields by position rather than by name, so a mismatched variant
+// order silently decodes into the wrong variant instead of failing loudly
+// the way the old YAML/JSON envelope would have.
+pub const ZATEL_PROTOCOL_VERSION: u32 = 2;
+// Oldest protocol version this build still knows how to talk to.
+pub const ZATEL_PROTOCOL_VERSION_MIN: u32 = 1;
+
+// Default upper bound on how long a single ipc_recv/ipc_exec call is
+// allowed to block waiting on a peer, so a stalled client or plugin
+// cannot pin a task forever.
+pub const ZATEL_IPC_TIMEOUT: Duration = Duration::from_secs(30);
+// Default cap on how many client connections an accept loop will service
+// concurrently.
+pub const ZATEL_MAX_CONCURRENT_CONNECTIONS: usize = 256;
+
+// Upper bound on how long the wire-format handshake (the single
+// read-then-write, or write-then-read, pair done before any
+// ZatelIpcMessage is exchanged) may take. Without it, a peer that accepts
+// a connection but never writes or reads its half of the handshake leaves
+// ipc_accept/ipc_handshake_client stuck forever, pinning whatever
+// connection-limiter permit the caller is holding for that connection
+// (see daemon::zateld and plugin::run_plugin) until ZATEL_MAX_CONCURRENT_
+// CONNECTIONS such stalls wedge the whole accept loop.
+const ZATEL_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Preferred text format for human-authored payloads embedded as strings
+// in ZatelIpcData (e.g. QueryIfaceInfoReply), such as the CLI asking for
+// JSON instead of YAML. Negotiated once, right after the socket connects,
+// and held for the lifetime of that connection so both ends stay in sync
+// without re-declaring it on every message. The ZatelIpcMessage envelope
+// itself is always bincode, regardless of this setting - see ipc_send/
+// ipc_recv_get_data.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ZatelWireFormat {
+    Yaml,
+    Json,
+}
+
+impl ZatelWireFormat {
+    fn to_byte(self) -> u8 {
+        match self {
+            ZatelWireFormat::Yaml => 0,
+            ZatelWireFormat::Json => 1,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Result<Self, ZatelError> {
+        match byte {
+            0 => Ok(ZatelWireFormat::Yaml),
+            1 => Ok(ZatelWireFormat::Json),
+            _ => Err(ZatelError::bug(format!(
+                "Invalid ZatelWireFormat byte received: {}",
+                byte
+            ))),
+        }
+    }
+}
+
+// A connected IPC socket together with the wire format negotiated for it.
+// Generic over the underlying byte stream so the same framing, wire-format
+// negotiation and message loop work unchanged whether `S` is a Unix local
+// socket (plugin connections, and the daemon's default client transport)
+// or a TCP/TLS connection (see crate::transport) used for remote
+// administration.
+pub struct ZatelIpcStream<S> {
+    pub stream: S,
+    pub format: ZatelWireFormat,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub enum ZatelIpcData {
     Error(ZatelError),
+    QueryProtocolVersion,
+    QueryProtocolVersionReply(u32),
+    // Challenge-response plugin authentication, see crate::auth.
+    AuthChallenge([u8; 32]),
+    AuthResponse {
+        pubkey: [u8; 32],
+        // serde only derives Serialize/Deserialize for arrays up to 32
+        // elements; BigArray fills that gap for the 64-byte ed25519
+        // signature.
+        #[serde(with = "BigArray")]
+        signature: [u8; 64],
+    },
     QueryPluginInfo,
     QueryPluginInfoReply(ZatelPluginInfo),
     QueryIfaceInfo(String),
@@ -43,12 +135,60 @@ pub enum ZatelIpcData {
     QuerySavedConfReply(ZatelConnection),
     QuerySavedConfAll,
     QuerySavedConfAllReply(Vec<ZatelConnection>),
+    // Plugin with ZatelPluginCapacity::Config capacity should support
+    // DeleteConf and reply with the UUID it removed.
+    DeleteConf(String),
+    DeleteConfReply(String),
+    // Apply a saved connection's config to the live runtime (the `ztl
+    // connection up` subcommand). The daemon does not dispatch this
+    // variant to plugins directly -- it re-runs SaveConf's reconcile
+    // logic against the connection already on file, so plugins only ever
+    // see ValidateConf/SaveConf (see daemon::reconcile).
+    ActivateConf(String),
+    ActivateConfReply(ZatelConnection),
+    // Tear down a saved connection's config from the live runtime (the
+    // `ztl connection down` subcommand). Plugin with
+    // ZatelPluginCapacity::Config capacity should support DeactivateConf
+    // and reply with whatever portion of the connection it deactivated.
+    DeactivateConf(String),
+    DeactivateConfReply(ZatelConnection),
+    // Plugin with ZatelPluginCapacity::Firewall capacity should support
+    // querying, validating and applying declarative firewall rule sets,
+    // mirroring the QueryIfaceInfo/ValidateConf/SaveConf flows above.
+    QueryFirewallRules,
+    QueryFirewallRulesReply(String),
+    ValidateFirewallRules(String),
+    ValidateFirewallRulesReply(String),
+    ApplyFirewallRules(String),
+    ApplyFirewallRulesReply(String),
+    // Re-scan the plugin directory and reconcile the running set against
+    // it: newly appeared plugins are started, ones no longer present are
+    // asked to terminate, and unchanged ones are left running untouched,
+    // so connections already in flight against the previous plugin set
+    // are never dropped. See daemon::plugin.
+    ReloadPlugins,
+    ReloadPluginsReply {
+        added: Vec<String>,
+        removed: Vec<String>,
+        failed: Vec<String>,
+    },
     ConnectionClosed,
     None,
 }
 
+// Monotonic source for ZatelIpcMessage::request_id, so a caller that fires
+// off several requests on the same stream before reading any replies can
+// match each reply back to the request that produced it instead of
+// assuming strict request/reply ordering.
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1);
+
+fn next_request_id() -> u64 {
+    NEXT_REQUEST_ID.fetch_add(1, Ordering::Relaxed)
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ZatelIpcMessage {
+    pub request_id: u64,
     pub data: ZatelIpcData,
     // TODO: include logs also
     pub log: Option<Vec<ZatelLogEntry>>,
@@ -57,12 +197,14 @@ pub struct ZatelIpcMessage {
 impl ZatelIpcMessage {
     pub fn new(data: ZatelIpcData) -> Self {
         ZatelIpcMessage {
+            request_id: next_request_id(),
             data: data,
             log: None,
         }
     }
     pub fn new_with_log(data: ZatelIpcData, log: Vec<ZatelLogEntry>) -> Self {
         ZatelIpcMessage {
+            request_id: next_request_id(),
             data: data,
             log: Some(log),
         }
@@ -81,6 +223,11 @@ impl ZatelIpcMessage {
             ZatelIpcData::QueryIfaceInfoReply(s) => Ok(&s),
             ZatelIpcData::ValidateConf(s) => Ok(&s),
             ZatelIpcData::ValidateConfReply(s) => Ok(&s),
+            ZatelIpcData::QueryFirewallRulesReply(s) => Ok(&s),
+            ZatelIpcData::ValidateFirewallRules(s) => Ok(&s),
+            ZatelIpcData::ValidateFirewallRulesReply(s) => Ok(&s),
+            ZatelIpcData::ApplyFirewallRules(s) => Ok(&s),
+            ZatelIpcData::ApplyFirewallRulesReply(s) => Ok(&s),
             _ => Err(ZatelError::invalid_argument(format!(
                 "{:?} does not holding string in data",
                 &self.data
@@ -89,15 +236,40 @@ impl ZatelIpcMessage {
     }
 }
 
-pub fn ipc_bind() -> Result<UnixListener, ZatelError> {
+// Names starting with '/' are bound as a real filesystem path (the
+// fallback used on platforms without namespaced local sockets, and where
+// stale files from a crashed previous run may still need clearing out);
+// anything else is a platform-namespaced name - an abstract socket on
+// Linux, a named pipe on Windows - which the OS reclaims on its own once
+// the last handle to it closes.
+pub(crate) fn to_local_socket_name(
+    socket_name: &str,
+) -> Result<interprocess::local_socket::Name<'_>, ZatelError> {
+    let name = if socket_name.starts_with('/') {
+        socket_name.to_fs_name::<GenericFilePath>()
+    } else {
+        socket_name.to_ns_name::<GenericNamespaced>()
+    };
+    name.map_err(|e| {
+        ZatelError::bug(format!(
+            "Invalid local socket name {}: {}",
+            socket_name, e
+        ))
+    })
+}
+
+pub fn ipc_bind() -> Result<LocalSocketListener, ZatelError> {
     ipc_bind_with_path(DEFAULT_SOCKET_PATH)
 }
 
 pub fn ipc_bind_with_path(
     socket_path: &str,
-) -> Result<UnixListener, ZatelError> {
-    remove_file(socket_path).ok();
-    match UnixListener::bind(socket_path) {
+) -> Result<LocalSocketListener, ZatelError> {
+    // Clear out a stale path left behind by a previous, uncleanly stopped
+    // instance before binding.
+    ipc_unbind_with_path(socket_path);
+    let name = to_local_socket_name(socket_path)?;
+    match LocalSocketListener::bind(name) {
         Err(e) => Err(ZatelError::bug(format!(
             "Failed to bind socket {}: {}",
             socket_path, e
@@ -106,28 +278,141 @@ pub fn ipc_bind_with_path(
     }
 }
 
-pub async fn ipc_connect() -> Result<UnixStream, ZatelError> {
+// Remove the socket file left behind by `ipc_bind_with_path` on a graceful
+// shutdown, so the next start-up does not have to clear a stale path
+// itself. Namespaced sockets need no cleanup here -- the OS reclaims them
+// once the listener is dropped.
+pub fn ipc_unbind() {
+    ipc_unbind_with_path(DEFAULT_SOCKET_PATH);
+}
+
+pub fn ipc_unbind_with_path(socket_path: &str) {
+    if socket_path.starts_with('/') {
+        remove_file(socket_path).ok();
+    }
+}
+
+pub async fn ipc_connect() -> Result<ZatelIpcStream<LocalSocketStream>, ZatelError>
+{
     ipc_connect_with_path(DEFAULT_SOCKET_PATH).await
 }
 
 pub async fn ipc_connect_with_path(
     socket_path: &str,
-) -> Result<UnixStream, ZatelError> {
-    match UnixStream::connect(socket_path).await {
+) -> Result<ZatelIpcStream<LocalSocketStream>, ZatelError> {
+    let name = to_local_socket_name(socket_path)?;
+    let stream = match LocalSocketStream::connect(name).await {
+        Err(e) => {
+            return Err(ZatelError::bug(format!(
+                "Failed to connect socket {}: {}",
+                socket_path, e
+            )))
+        }
+        Ok(l) => l,
+    };
+    ipc_handshake_client(stream).await
+}
+
+// Complete the client side of the wire-format handshake on a freshly
+// connected stream of any transport, before any ZatelIpcMessage is
+// exchanged on it.
+pub async fn ipc_handshake_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+) -> Result<ZatelIpcStream<S>, ZatelError> {
+    let mut stream = stream;
+    // JSON is the default proposal: it is cheaper to parse for large
+    // replies and lets non-Rust clients consume the socket. The peer
+    // confirms (or, in future, downgrades) the proposal.
+    let format = match timeout(
+        ZATEL_HANDSHAKE_TIMEOUT,
+        negotiate_format_client(&mut stream, ZatelWireFormat::Json),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "Wire format handshake did not complete within {:?}",
+                ZATEL_HANDSHAKE_TIMEOUT
+            )))
+        }
+    };
+    Ok(ZatelIpcStream { stream, format })
+}
+
+// Complete the server side of the wire-format handshake on a freshly
+// accepted connection, before any ZatelIpcMessage is exchanged on it.
+// Generic over the stream type so it works the same whether `stream` came
+// from a Unix listener or a TCP/TLS one (see crate::transport).
+pub async fn ipc_accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+) -> Result<ZatelIpcStream<S>, ZatelError> {
+    let mut stream = stream;
+    let format = match timeout(
+        ZATEL_HANDSHAKE_TIMEOUT,
+        negotiate_format_server(&mut stream),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "Wire format handshake did not complete within {:?}",
+                ZATEL_HANDSHAKE_TIMEOUT
+            )))
+        }
+    };
+    Ok(ZatelIpcStream { stream, format })
+}
+
+async fn negotiate_format_client<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    preferred: ZatelWireFormat,
+) -> Result<ZatelWireFormat, ZatelError> {
+    if let Err(e) = stream.write_u8(preferred.to_byte()).await {
+        return Err(ZatelError::bug(format!(
+            "Failed to send wire format proposal: {}",
+            e
+        )));
+    }
+    match stream.read_u8().await {
+        Ok(b) => ZatelWireFormat::from_byte(b),
         Err(e) => Err(ZatelError::bug(format!(
-            "Failed to connect socket {}: {}",
-            socket_path, e
+            "Failed to read wire format confirmation: {}",
+            e
         ))),
-        Ok(l) => Ok(l),
     }
 }
 
-pub async fn ipc_send(
-    stream: &mut UnixStream,
+async fn negotiate_format_server<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<ZatelWireFormat, ZatelError> {
+    let proposed = match stream.read_u8().await {
+        Ok(b) => ZatelWireFormat::from_byte(b)?,
+        Err(e) => {
+            return Err(ZatelError::bug(format!(
+                "Failed to read wire format proposal: {}",
+                e
+            )))
+        }
+    };
+    // Both formats are always supported today, so the proposal is simply
+    // echoed back to confirm it.
+    if let Err(e) = stream.write_u8(proposed.to_byte()).await {
+        return Err(ZatelError::bug(format!(
+            "Failed to send wire format confirmation: {}",
+            e
+        )));
+    }
+    Ok(proposed)
+}
+
+pub async fn ipc_send<S: AsyncRead + AsyncWrite + Unpin>(
+    ipc_stream: &mut ZatelIpcStream<S>,
     message: &ZatelIpcMessage,
 ) -> Result<(), ZatelError> {
-    let message_string = match serde_yaml::to_string(message) {
-        Ok(s) => s,
+    let message_bytes = match bincode::serialize(message) {
+        Ok(b) => b,
         Err(e) => {
             return Err(ZatelError::invalid_argument(format!(
                 "Invalid IPC message - failed to serialize {:?}: {}",
@@ -135,15 +420,16 @@ pub async fn ipc_send(
             )))
         }
     };
-    let message_bytes = message_string.as_bytes();
-    if let Err(e) = stream.write_u32(message_bytes.len() as u32).await {
+    if let Err(e) =
+        ipc_stream.stream.write_u32(message_bytes.len() as u32).await
+    {
         return Err(ZatelError::bug(format!(
             "Failed to write message size {} to socket: {}",
             message_bytes.len(),
             e
         )));
     };
-    if let Err(e) = stream.write_all(message_bytes).await {
+    if let Err(e) = ipc_stream.stream.write_all(&message_bytes).await {
         return Err(ZatelError::bug(format!(
             "Failed to write message to socket: {}",
             e
@@ -152,8 +438,8 @@ pub async fn ipc_send(
     Ok(())
 }
 
-async fn ipc_recv_get_size(
-    stream: &mut UnixStream,
+async fn ipc_recv_get_size<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
 ) -> Result<usize, ZatelError> {
     match stream.read_u32().await {
         Err(e) => {
@@ -171,8 +457,8 @@ async fn ipc_recv_get_size(
     }
 }
 
-async fn ipc_recv_get_data(
-    stream: &mut UnixStream,
+async fn ipc_recv_get_data<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
     message_size: usize,
 ) -> Result<ZatelIpcMessage, ZatelError> {
     let mut buffer = vec![0u8; message_size];
@@ -187,11 +473,15 @@ async fn ipc_recv_get_data(
             )));
         }
     }
-    match serde_yaml::from_slice::<ZatelIpcMessage>(&buffer) {
-        Err(e) => Err(ZatelError::bug(format!(
-            "Invalid message recieved: {:?}: {}",
-            buffer, e
-        ))),
+    let message =
+        bincode::deserialize::<ZatelIpcMessage>(&buffer).map_err(|e| {
+            ZatelError::bug(format!(
+                "Invalid message recieved: {:?}: {}",
+                buffer, e
+            ))
+        });
+    match message {
+        Err(e) => Err(e),
         Ok(m) => match &m.data {
             ZatelIpcData::Error(e) => Err(e.clone()),
             _ => Ok(m),
@@ -199,22 +489,22 @@ async fn ipc_recv_get_data(
     }
 }
 
-pub async fn ipc_recv(
-    stream: &mut UnixStream,
+pub async fn ipc_recv<S: AsyncRead + AsyncWrite + Unpin>(
+    ipc_stream: &mut ZatelIpcStream<S>,
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    let message_size = ipc_recv_get_size(stream).await?;
+    let message_size = ipc_recv_get_size(&mut ipc_stream.stream).await?;
     if message_size == 0 {
         return Ok(ZatelIpcMessage::new(ZatelIpcData::ConnectionClosed));
     }
-    ipc_recv_get_data(stream, message_size).await
+    ipc_recv_get_data(&mut ipc_stream.stream, message_size).await
 }
 
 // Return error if data size execeed IPC_SAFE_SIZE
 // Normally used by daemon where client can not be trusted.
-pub async fn ipc_recv_safe(
-    stream: &mut UnixStream,
+pub async fn ipc_recv_safe<S: AsyncRead + AsyncWrite + Unpin>(
+    ipc_stream: &mut ZatelIpcStream<S>,
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    let message_size = ipc_recv_get_size(stream).await?;
+    let message_size = ipc_recv_get_size(&mut ipc_stream.stream).await?;
     if message_size == 0 {
         return Ok(ZatelIpcMessage::new(ZatelIpcData::ConnectionClosed));
     }
@@ -224,13 +514,13 @@ pub async fn ipc_recv_safe(
             IPC_SAFE_SIZE
         )));
     }
-    ipc_recv_get_data(stream, message_size).await
+    ipc_recv_get_data(&mut ipc_stream.stream, message_size).await
 }
 
-pub async fn ipc_exec(
-    stream: &mut UnixStream,
+pub async fn ipc_exec<S: AsyncRead + AsyncWrite + Unpin>(
+    ipc_stream: &mut ZatelIpcStream<S>,
     message: &ZatelIpcMessage,
 ) -> Result<ZatelIpcMessage, ZatelError> {
-    ipc_send(stream, message).await?;
-    ipc_recv(stream).await
+    ipc_send(ipc_stream, message).await?;
+    ipc_recv(ipc_stream).await
 }
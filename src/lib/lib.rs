@@ -12,21 +12,47 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod auth;
 mod connection;
+mod duration;
 mod error;
 mod ipc;
 mod logging;
 mod plugin;
+mod transport;
 mod yaml;
 
+pub use crate::auth::{
+    cached_authorized_plugin_keys, daemon_identity, enroll_bootstrapped_plugin_key,
+    gen_auth_nonce, load_authorized_plugin_keys, load_or_create_identity,
+    sign_auth_nonce, verify_auth_response, ZATEL_AUTHORIZED_PLUGIN_KEYS_PATH,
+    ZATEL_DAEMON_IDENTITY_PATH,
+};
 pub use crate::connection::ZatelConnection;
+pub use crate::duration::{duration_from_env, parse_duration};
 pub use crate::error::ZatelError;
 pub use crate::ipc::{
-    ipc_bind, ipc_bind_with_path, ipc_connect, ipc_connect_with_path, ipc_exec,
-    ipc_recv, ipc_recv_safe, ipc_send, ZatelIpcData, ZatelIpcMessage,
+    ipc_accept, ipc_bind, ipc_bind_with_path, ipc_connect,
+    ipc_connect_with_path, ipc_exec, ipc_handshake_client, ipc_recv,
+    ipc_recv_safe, ipc_send, ipc_unbind, ipc_unbind_with_path, ZatelIpcData,
+    ZatelIpcMessage, ZatelIpcStream, ZatelWireFormat, ZATEL_IPC_TIMEOUT,
+    ZATEL_MAX_CONCURRENT_CONNECTIONS, ZATEL_PROTOCOL_VERSION,
+    ZATEL_PROTOCOL_VERSION_MIN,
+};
+pub use crate::logging::{
+    capture_logs, forward_plugin_log_entry, init_logging, log_level_from_env,
+    ZatelLogEntry, ZatelLogLevel,
 };
-pub use crate::logging::{ZatelLogEntry, ZatelLogLevel};
 pub use crate::plugin::{
-    ipc_plugin_exec, ipc_plugins_exec, ZatelPluginCapacity, ZatelPluginInfo,
+    ipc_plugin_exec, ipc_plugins_exec, run_plugin, ZatelPlugin,
+    ZatelPluginCapacity, ZatelPluginInfo,
+};
+pub use crate::transport::{
+    bind_transport, connect_transport, default_server_addr,
+    server_addr_from_env, unbind_transport, ZatelListener, ZatelServerAddr,
+    ZatelStream,
+};
+pub use crate::yaml::{
+    merge_yaml_lists, merge_yaml_mappings, merge_yaml_mappings_recursive,
+    merge_yaml_mappings_three_way, yaml_diff, yaml_mapping_converged,
 };
-pub use crate::yaml::{merge_yaml_lists, merge_yaml_mappings};
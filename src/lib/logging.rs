@@ -0,0 +1,206 @@
+//    Copyright 2021 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::cell::RefCell;
+use std::io::Write;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+
+use crate::ZatelError;
+
+tokio::task_local! {
+    // Buffers log records emitted while a `capture_logs()`-scoped future
+    // is running, so they can be attached to that one request's reply
+    // instead of only ever going to the daemon/plugin's own stderr or
+    // syslog output.
+    static LOG_CAPTURE: RefCell<Vec<ZatelLogEntry>>;
+}
+
+const ZATEL_LOG_LEVEL_ENV: &str = "ZATEL_LOG_LEVEL";
+const ZATEL_LOG_TARGET_ENV: &str = "ZATEL_LOG_TARGET";
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ZatelLogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl ZatelLogLevel {
+    fn to_log_level(&self) -> Level {
+        match self {
+            ZatelLogLevel::Debug => Level::Debug,
+            ZatelLogLevel::Info => Level::Info,
+            ZatelLogLevel::Warn => Level::Warn,
+            ZatelLogLevel::Error => Level::Error,
+        }
+    }
+
+    fn from_log_level(level: Level) -> Self {
+        match level {
+            Level::Error => ZatelLogLevel::Error,
+            Level::Warn => ZatelLogLevel::Warn,
+            Level::Info => ZatelLogLevel::Info,
+            Level::Debug | Level::Trace => ZatelLogLevel::Debug,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ZatelLogEntry {
+    pub level: ZatelLogLevel,
+    pub message: String,
+}
+
+impl ZatelLogEntry {
+    pub fn new(level: ZatelLogLevel, message: String) -> Self {
+        ZatelLogEntry {
+            level: level,
+            message: message,
+        }
+    }
+}
+
+// Re-emit a log entry a plugin sent back over IPC through this process's
+// own logging pipeline, at the severity the plugin reported, instead of
+// silently dropping it once ZatelIpcMessage.data has been consumed.
+pub fn forward_plugin_log_entry(plugin_name: &str, entry: &ZatelLogEntry) {
+    log::log!(
+        entry.level.to_log_level(),
+        "{}: {}",
+        plugin_name,
+        entry.message
+    );
+}
+
+// Mirrors the "LEVEL: message" lines the daemon and plugins used to print
+// via eprintln! directly, so existing log scraping built against stderr
+// keeps working when ZATEL_LOG_TARGET is unset or "stderr".
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stderr().flush();
+    }
+}
+
+// Wraps whichever backend init_logging() installed (stderr or syslog) so
+// every record also lands in the current task's LOG_CAPTURE buffer, if
+// one is active. This is what lets a plugin attach the log lines produced
+// while handling a single request onto that request's reply, on top of
+// still going to the backend exactly as before.
+struct CapturingLogger {
+    inner: Box<dyn Log>,
+}
+
+impl Log for CapturingLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.log(record);
+        if self.enabled(record.metadata()) {
+            let _ = LOG_CAPTURE.try_with(|buf| {
+                buf.borrow_mut().push(ZatelLogEntry::new(
+                    ZatelLogLevel::from_log_level(record.level()),
+                    record.args().to_string(),
+                ));
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// Run `f`, capturing every log record emitted by it (via the `log` crate
+// facade, from any function it calls) into a buffer, and return both the
+// future's own result and the captured entries. Used by run_plugin to
+// attach the diagnostics produced while handling one request onto that
+// request's reply, so `ztl -v` can show exactly which plugin logged what.
+pub async fn capture_logs<F, T>(f: F) -> (T, Vec<ZatelLogEntry>)
+where
+    F: std::future::Future<Output = T>,
+{
+    LOG_CAPTURE
+        .scope(RefCell::new(Vec::new()), async move {
+            let result = f.await;
+            let entries = LOG_CAPTURE.with(|buf| buf.borrow().clone());
+            (result, entries)
+        })
+        .await
+}
+
+// Parse ZATEL_LOG_LEVEL (debug/info/warn/error, case-insensitive),
+// defaulting to Info when unset or unrecognized.
+pub fn log_level_from_env() -> LevelFilter {
+    match std::env::var(ZATEL_LOG_LEVEL_ENV) {
+        Ok(s) => match s.to_lowercase().as_str() {
+            "debug" => LevelFilter::Debug,
+            "info" => LevelFilter::Info,
+            "warn" => LevelFilter::Warn,
+            "error" => LevelFilter::Error,
+            _ => LevelFilter::Info,
+        },
+        Err(_) => LevelFilter::Info,
+    }
+}
+
+// Install the logging backend for this process: syslog (landing in
+// journald with proper priorities) when ZATEL_LOG_TARGET=syslog, stderr
+// otherwise. `ident` identifies this process (e.g. "ztld",
+// "zatel_plugin_nispor") in syslog output. Should be called once, at the
+// very start of main().
+pub fn init_logging(ident: &str) -> Result<(), ZatelError> {
+    let level = log_level_from_env();
+    let use_syslog = matches!(
+        std::env::var(ZATEL_LOG_TARGET_ENV).as_deref(),
+        Ok("syslog")
+    );
+
+    let inner: Box<dyn Log> = if use_syslog {
+        let formatter = syslog::Formatter3164 {
+            facility: syslog::Facility::LOG_DAEMON,
+            hostname: None,
+            process: ident.to_string(),
+            pid: std::process::id() as i32,
+        };
+        let logger = syslog::unix(formatter).map_err(|e| {
+            ZatelError::bug(format!("Failed to connect to syslog: {}", e))
+        })?;
+        Box::new(syslog::BasicLogger::new(logger))
+    } else {
+        Box::new(StderrLogger)
+    };
+    log::set_boxed_logger(Box::new(CapturingLogger { inner })).map_err(
+        |e| ZatelError::bug(format!("Failed to install logger: {}", e)),
+    )?;
+    log::set_max_level(level);
+    Ok(())
+}
@@ -12,19 +12,53 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use ed25519_dalek::{Keypair, PublicKey};
 use futures::future::join_all;
+use interprocess::local_socket::tokio::LocalSocketStream;
+use log::{debug, error, warn};
 use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
 
 use crate::{
-    ipc_connect_with_path, ipc_recv, ipc_send, ZatelError, ZatelIpcMessage,
+    cached_authorized_plugin_keys, capture_logs, daemon_identity,
+    duration_from_env, forward_plugin_log_entry, gen_auth_nonce, ipc_accept,
+    ipc_bind_with_path, ipc_connect_with_path, ipc_exec, ipc_recv, ipc_send,
+    load_or_create_identity, sign_auth_nonce, verify_auth_response,
+    ZatelConnection, ZatelError, ZatelIpcData, ZatelIpcMessage, ZatelIpcStream,
+    ZatelWireFormat, ZATEL_AUTHORIZED_PLUGIN_KEYS_PATH, ZATEL_IPC_TIMEOUT,
+    ZATEL_MAX_CONCURRENT_CONNECTIONS, ZATEL_PROTOCOL_VERSION,
+    ZATEL_PROTOCOL_VERSION_MIN,
 };
 
+// Upper bound on how long the protocol-version and auth handshake steps
+// may take before a plugin is treated as unresponsive. Fixed rather than
+// covered by ZATEL_PLUGIN_TIMEOUT: a plugin that's slow to come up is a
+// different failure than one that's slow to answer a real request.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Overrides how long a single plugin request (QueryIfaceInfo, SaveConf,
+// etc, not the protocol/auth handshake) is given to reply before that
+// plugin's contribution is dropped, accepting the same compact duration
+// strings as parse_duration(). Falls back to ZATEL_IPC_TIMEOUT when unset
+// or invalid, so a stalled plugin never blocks the aggregate reply
+// indefinitely even without any configuration. Resolved once per
+// ipc_plugins_exec() dispatch and threaded down, rather than re-read for
+// every plugin and every handshake step.
+const ZATEL_PLUGIN_TIMEOUT_ENV: &str = "ZATEL_PLUGIN_TIMEOUT";
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum ZatelPluginCapacity {
     Query,
     Apply,
     Config,
+    Firewall,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -32,6 +66,13 @@ pub struct ZatelPluginInfo {
     pub name: String,
     pub socket_path: String,
     pub capacities: Vec<ZatelPluginCapacity>,
+    // Filled in by run_plugin/handle_msg from the plugin's own persisted
+    // identity before QueryPluginInfoReply goes out, not by the plugin
+    // author, so a freshly spawned plugin can be enrolled into the
+    // daemon's authorized-keys allow-list (see
+    // enroll_bootstrapped_plugin_key) without the plugin itself knowing
+    // or caring about authentication.
+    pub pubkey: [u8; 32],
 }
 
 impl ZatelPluginInfo {
@@ -40,6 +81,7 @@ impl ZatelPluginInfo {
             name: name.into(),
             socket_path: "".into(),
             capacities: capacities,
+            pubkey: [0u8; 32],
         }
     }
 }
@@ -47,40 +89,684 @@ impl ZatelPluginInfo {
 pub async fn ipc_plugin_exec(
     plugin_info: &ZatelPluginInfo,
     ipc_msg: &ZatelIpcMessage,
+    plugin_timeout: Duration,
 ) -> Result<ZatelIpcMessage, ZatelError> {
     let mut stream = ipc_connect_with_path(&plugin_info.socket_path).await?;
+
+    check_plugin_protocol_version(&mut stream, &plugin_info.name).await?;
+    authenticate_plugin(&mut stream, &plugin_info.name).await?;
+    answer_plugin_challenge(&mut stream, &plugin_info.name).await?;
+
     ipc_send(&mut stream, ipc_msg).await?;
-    // TODO: Handle timeout
-    ipc_recv(&mut stream).await
+    match timeout(plugin_timeout, ipc_recv(&mut stream)).await {
+        Ok(r) => r,
+        Err(_) => Err(ZatelError::timeout(format!(
+            "Plugin {} did not reply within {:?}",
+            plugin_info.name, plugin_timeout
+        ))),
+    }
+}
+
+// Plugin sockets live in the filesystem and any local process can connect
+// to them, so prove the peer on the other end really is the plugin it
+// claims to be before sending any QueryIfaceInfo/SaveConf/etc traffic:
+// send a fresh nonce and check the signature the plugin returns against
+// our allow-list of authorized plugin public keys. The allow-list itself
+// is loaded from disk once and cached (see cached_authorized_plugin_keys)
+// rather than re-read on every call, since this runs on every single
+// plugin request.
+async fn authenticate_plugin(
+    stream: &mut ZatelIpcStream<LocalSocketStream>,
+    plugin_name: &str,
+) -> Result<(), ZatelError> {
+    let authorized_keys =
+        cached_authorized_plugin_keys(ZATEL_AUTHORIZED_PLUGIN_KEYS_PATH);
+
+    let nonce = gen_auth_nonce();
+    let reply = match timeout(
+        HANDSHAKE_TIMEOUT,
+        ipc_exec(stream, &ZatelIpcMessage::new(ZatelIpcData::AuthChallenge(
+            nonce,
+        ))),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "Plugin {} did not complete the auth handshake within {:?}",
+                plugin_name, HANDSHAKE_TIMEOUT
+            )))
+        }
+    };
+
+    let (pubkey, signature) = match reply.data {
+        ZatelIpcData::AuthResponse { pubkey, signature } => {
+            (pubkey, signature)
+        }
+        _ => {
+            return Err(ZatelError::plugin_error(format!(
+                "Plugin {} did not reply with AuthResponse: {:?}",
+                plugin_name, reply
+            )))
+        }
+    };
+
+    verify_auth_response(&authorized_keys, &nonce, &pubkey, &signature)
+        .map_err(|e| {
+            ZatelError::plugin_error(format!(
+                "Plugin {} failed authentication: {}",
+                plugin_name, e
+            ))
+        })
+}
+
+// Plugin sockets are only ever authenticated in one direction above: the
+// daemon proves the plugin is who it claims to be, but not the other way
+// around. Since these sockets are reachable by any local process (an
+// abstract-namespace name is not a secret - see /proc/net/unix), a plugin
+// has no way to tell a legitimate daemon apart from any other local
+// process issuing SaveConf/ValidateConf directly. To close that gap,
+// run_plugin has every plugin issue its own AuthChallenge back to the
+// client once the daemon's plugin-authentication round above completes
+// (see challenge_client); this answers that challenge using the daemon's
+// own persisted identity so a genuine daemon can satisfy it.
+async fn answer_plugin_challenge(
+    stream: &mut ZatelIpcStream<LocalSocketStream>,
+    plugin_name: &str,
+) -> Result<(), ZatelError> {
+    let challenge = match timeout(HANDSHAKE_TIMEOUT, ipc_recv(stream)).await {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "Plugin {} did not challenge the daemon within {:?}",
+                plugin_name, HANDSHAKE_TIMEOUT
+            )))
+        }
+    };
+    let nonce = match challenge.data {
+        ZatelIpcData::AuthChallenge(nonce) => nonce,
+        _ => {
+            return Err(ZatelError::plugin_error(format!(
+                "Plugin {} did not send an AuthChallenge: {:?}",
+                plugin_name, challenge
+            )))
+        }
+    };
+
+    let identity = daemon_identity()?;
+    let (pubkey, signature) = sign_auth_nonce(&identity, &nonce);
+    let response = ZatelIpcMessage::new(ZatelIpcData::AuthResponse {
+        pubkey,
+        signature,
+    });
+    match timeout(HANDSHAKE_TIMEOUT, ipc_send(stream, &response)).await {
+        Ok(r) => r,
+        Err(_) => Err(ZatelError::timeout(format!(
+            "Plugin {} did not receive the daemon's AuthResponse within \
+            {:?}",
+            plugin_name, HANDSHAKE_TIMEOUT
+        ))),
+    }
 }
 
+// Negotiate the protocol version with the plugin before sending any real
+// request, so a plugin built against an incompatible ZatelIpcData enum is
+// rejected cleanly instead of failing later with an opaque deserialize
+// error.
+async fn check_plugin_protocol_version(
+    stream: &mut ZatelIpcStream<LocalSocketStream>,
+    plugin_name: &str,
+) -> Result<(), ZatelError> {
+    let reply = match timeout(
+        HANDSHAKE_TIMEOUT,
+        ipc_exec(
+            stream,
+            &ZatelIpcMessage::new(ZatelIpcData::QueryProtocolVersion),
+        ),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "Plugin {} did not reply to QueryProtocolVersion within {:?}",
+                plugin_name, HANDSHAKE_TIMEOUT
+            )))
+        }
+    };
+    let plugin_version = match reply.data {
+        ZatelIpcData::QueryProtocolVersionReply(v) => v,
+        _ => {
+            return Err(ZatelError::plugin_error(format!(
+                "Plugin {} did not reply with QueryProtocolVersionReply: \
+                {:?}",
+                plugin_name, reply
+            )))
+        }
+    };
+    if plugin_version < ZATEL_PROTOCOL_VERSION_MIN
+        || plugin_version > ZATEL_PROTOCOL_VERSION
+    {
+        return Err(ZatelError::plugin_error(format!(
+            "Plugin {} protocol version {} is not within supported range \
+            {}-{}, skipping",
+            plugin_name,
+            plugin_version,
+            ZATEL_PROTOCOL_VERSION_MIN,
+            ZATEL_PROTOCOL_VERSION
+        )));
+    }
+    Ok(())
+}
+
+// Dispatches `ipc_msg` to every plugin advertising `capacity` and returns
+// each plugin's raw reply paired with the name of the plugin that sent it
+// (callers pull whatever ZatelIpcData variant or get_data_str() string
+// they expect back out of these themselves, and can attribute a
+// malformed-reply warning to the right plugin). Every reply's log entries
+// are both forwarded into this process's own log immediately and,
+// prefixed with the plugin's name, left attached to the returned
+// ZatelIpcMessage so a caller assembling the final client-facing reply
+// can surface them too (see daemon::zateld's handlers and `ztl -v`).
 pub async fn ipc_plugins_exec(
     ipc_msg: &ZatelIpcMessage,
     plugins: &[ZatelPluginInfo],
     capacity: &ZatelPluginCapacity,
-) -> Vec<String> {
+) -> Vec<(String, ZatelIpcMessage)> {
+    let plugin_timeout =
+        duration_from_env(ZATEL_PLUGIN_TIMEOUT_ENV, ZATEL_IPC_TIMEOUT);
+
+    let mut matched_plugins = Vec::new();
     let mut replys_async = Vec::new();
     for plugin_info in plugins {
         if plugin_info.capacities.contains(capacity) {
-            replys_async.push(ipc_plugin_exec(plugin_info, &ipc_msg));
+            matched_plugins.push(plugin_info);
+            replys_async
+                .push(ipc_plugin_exec(plugin_info, &ipc_msg, plugin_timeout));
         }
     }
     let replys = join_all(replys_async).await;
 
-    let mut reply_strs = Vec::new();
-    for reply in replys {
+    let mut reply_msgs = Vec::new();
+    for (plugin_info, reply) in matched_plugins.into_iter().zip(replys) {
         match reply {
-            Ok(r) => {
-                if let Ok(s) = r.get_data_str() {
-                    reply_strs.push(s.to_string());
-                } else {
-                    eprintln!("WARN: got invalid reply from plugin: {:?}", r);
+            Ok(mut r) => {
+                if let Some(log_entries) = r.log.as_mut() {
+                    for entry in log_entries.iter_mut() {
+                        forward_plugin_log_entry(&plugin_info.name, entry);
+                        entry.message =
+                            format!("{}: {}", plugin_info.name, entry.message);
+                    }
                 }
+                reply_msgs.push((plugin_info.name.clone(), r));
             }
             Err(e) => {
-                eprintln!("WARN: got error from plugin: {:?}", e);
+                warn!("got error from plugin {}: {:?}", plugin_info.name, e);
             }
         }
     }
-    reply_strs
+    reply_msgs
+}
+
+// A plugin implements this trait to get the full accept/dispatch/auth
+// event loop from `run_plugin` for free, instead of copying the
+// bind/accept/ipc_recv/dispatch/ipc_send boilerplate every plugin needs.
+// Only the handlers for the requests the plugin actually supports need to
+// be overridden; `capacities()` should list exactly the
+// `ZatelPluginCapacity` entries matching the methods overridden below, so
+// `QueryPluginInfoReply` accurately reflects what the plugin can do.
+#[async_trait]
+pub trait ZatelPlugin: Send + Sync + 'static {
+    fn name(&self) -> &str;
+
+    // Path to this plugin's persisted ed25519 identity, used to answer
+    // the daemon's AuthChallenge handshake.
+    fn identity_path(&self) -> &str;
+
+    fn capacities(&self) -> Vec<ZatelPluginCapacity>;
+
+    async fn query_plugin_info(&self) -> ZatelPluginInfo {
+        ZatelPluginInfo::new(self.name(), self.capacities())
+    }
+
+    async fn query_iface(
+        &self,
+        _iface_name: &str,
+        _format: ZatelWireFormat,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: query_iface is not supported",
+            self.name()
+        )))
+    }
+
+    async fn validate_conf(
+        &self,
+        _conf: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: validate_conf is not supported",
+            self.name()
+        )))
+    }
+
+    async fn apply(&self, _conf: &str) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: apply is not supported",
+            self.name()
+        )))
+    }
+
+    async fn save_conf(
+        &self,
+        _ztl_con: ZatelConnection,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: save_conf is not supported",
+            self.name()
+        )))
+    }
+
+    async fn query_saved_conf(
+        &self,
+        _uuid: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: query_saved_conf is not supported",
+            self.name()
+        )))
+    }
+
+    async fn query_saved_conf_all(
+        &self,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: query_saved_conf_all is not supported",
+            self.name()
+        )))
+    }
+
+    async fn delete_conf(
+        &self,
+        _uuid: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: delete_conf is not supported",
+            self.name()
+        )))
+    }
+
+    async fn deactivate_conf(
+        &self,
+        _uuid: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: deactivate_conf is not supported",
+            self.name()
+        )))
+    }
+
+    async fn query_firewall_rules(
+        &self,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: query_firewall_rules is not supported",
+            self.name()
+        )))
+    }
+
+    async fn validate_firewall_rules(
+        &self,
+        _rules: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: validate_firewall_rules is not supported",
+            self.name()
+        )))
+    }
+
+    async fn apply_firewall_rules(
+        &self,
+        _rules: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        Err(ZatelError::plugin_error(format!(
+            "{}: apply_firewall_rules is not supported",
+            self.name()
+        )))
+    }
+}
+
+// Owns the bind/accept/ipc_recv/dispatch/ipc_send loop so a plugin binary
+// only needs to provide a `ZatelPlugin` impl and a one-line `main`.
+// `daemon_pubkey_hex` is the hex-encoded ed25519 public key of the daemon
+// that spawned this plugin (see daemon::plugin::plugin_start), trusted
+// because it was handed to this process directly at spawn time rather
+// than asserted by whoever connects to the socket; every accepted
+// connection has to prove it holds the matching private key before this
+// plugin will act on anything it sends (see challenge_client).
+pub async fn run_plugin<P: ZatelPlugin>(
+    plugin: P,
+    socket_path: &str,
+    daemon_pubkey_hex: &str,
+) {
+    let identity = match load_or_create_identity(plugin.identity_path()) {
+        Ok(i) => Arc::new(i),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let trusted_daemon_key = match hex::decode(daemon_pubkey_hex)
+        .ok()
+        .and_then(|bytes| PublicKey::from_bytes(&bytes).ok())
+    {
+        Some(k) => k,
+        None => {
+            error!(
+                "{}: invalid daemon public key argument {:?}",
+                plugin.name(),
+                daemon_pubkey_hex
+            );
+            std::process::exit(1);
+        }
+    };
+
+    let listener = match ipc_bind_with_path(socket_path) {
+        Ok(l) => l,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
+    debug!("{}: listening on {}", plugin.name(), socket_path);
+
+    let plugin = Arc::new(plugin);
+    let connection_limiter =
+        Arc::new(Semaphore::new(ZATEL_MAX_CONCURRENT_CONNECTIONS));
+
+    loop {
+        match listener.accept().await {
+            Ok(stream) => {
+                let permit =
+                    match connection_limiter.clone().acquire_owned().await {
+                        Ok(p) => p,
+                        Err(e) => {
+                            error!(
+                                "{}: connection limiter semaphore closed: {}",
+                                plugin.name(),
+                                e
+                            );
+                            continue;
+                        }
+                    };
+                let identity = identity.clone();
+                let plugin = plugin.clone();
+                tokio::task::spawn(async move {
+                    let _permit = permit;
+                    match ipc_accept(stream).await {
+                        Ok(ipc_stream) => {
+                            handle_client(
+                                ipc_stream,
+                                plugin,
+                                identity,
+                                trusted_daemon_key,
+                            )
+                            .await
+                        }
+                        Err(e) => error!(
+                            "{}: failed to negotiate wire format: {}",
+                            plugin.name(),
+                            e
+                        ),
+                    }
+                });
+            }
+            Err(e) => {
+                error!("{}", e);
+            }
+        }
+    }
+}
+
+async fn shutdown_connection(
+    ipc_stream: &mut ZatelIpcStream<LocalSocketStream>,
+) {
+    if let Err(e) = ipc_stream.stream.shutdown().await {
+        error!("{}", e);
+    }
+}
+
+async fn handle_client<P: ZatelPlugin>(
+    mut ipc_stream: ZatelIpcStream<LocalSocketStream>,
+    plugin: Arc<P>,
+    identity: Arc<Keypair>,
+    trusted_daemon_key: PublicKey,
+) {
+    // Set once this connection's client has answered our own AuthChallenge
+    // (see challenge_client), right after it answers the daemon's opening
+    // AuthChallenge message - that reply is the signal that the generic
+    // protocol-version/auth handshake above has completed and it is our
+    // turn to challenge back.
+    let mut client_authenticated = false;
+    loop {
+        match timeout(ZATEL_IPC_TIMEOUT, ipc_recv(&mut ipc_stream)).await {
+            Ok(Ok(ipc_msg)) => match ipc_msg.data {
+                ZatelIpcData::ConnectionClosed => {
+                    shutdown_connection(&mut ipc_stream).await;
+                    break;
+                }
+                _ => {
+                    let request_id = ipc_msg.request_id;
+                    let answering_our_challenge =
+                        matches!(ipc_msg.data, ZatelIpcData::AuthChallenge(_));
+                    if requires_client_auth(&ipc_msg.data)
+                        && !client_authenticated
+                    {
+                        warn!(
+                            "{}: rejecting {:?} from a client that has not \
+                            completed authentication",
+                            plugin.name(),
+                            ipc_msg.data
+                        );
+                        shutdown_connection(&mut ipc_stream).await;
+                        break;
+                    }
+                    let (mut message, log_entries) = capture_logs(handle_msg(
+                        plugin.as_ref(),
+                        ipc_msg.data,
+                        ipc_stream.format,
+                        &identity,
+                    ))
+                    .await;
+                    message.request_id = request_id;
+                    if !log_entries.is_empty() {
+                        message.log = Some(log_entries);
+                    }
+                    debug!("{}: reply: {:?}", plugin.name(), &message);
+                    if let Err(e) = ipc_send(&mut ipc_stream, &message).await {
+                        error!(
+                            "{}: failed to send to daemon: {}",
+                            plugin.name(),
+                            e
+                        );
+                        break;
+                    }
+                    if answering_our_challenge && !client_authenticated {
+                        match challenge_client(
+                            &mut ipc_stream,
+                            &trusted_daemon_key,
+                            plugin.name(),
+                        )
+                        .await
+                        {
+                            Ok(()) => client_authenticated = true,
+                            Err(e) => {
+                                error!("{}", e);
+                                shutdown_connection(&mut ipc_stream).await;
+                                break;
+                            }
+                        }
+                    }
+                }
+            },
+            Ok(Err(e)) => {
+                error!("IPC error {}", e);
+                shutdown_connection(&mut ipc_stream).await;
+                break;
+            }
+            Err(_) => {
+                warn!(
+                    "{}: client idle for longer than {:?}",
+                    plugin.name(),
+                    ZATEL_IPC_TIMEOUT
+                );
+                shutdown_connection(&mut ipc_stream).await;
+                break;
+            }
+        }
+    }
+}
+
+// Everything except the handshake itself (protocol version negotiation,
+// the daemon authenticating us, and the read-only QueryPluginInfo the
+// daemon also uses to bootstrap a freshly spawned plugin into its
+// allow-list - see enroll_bootstrapped_plugin_key) has to wait for
+// challenge_client to confirm the connected client holds the daemon's
+// private key. Gating on this explicitly, rather than only issuing the
+// challenge reactively, matters: otherwise a client that never answers
+// (or never even sends) our AuthChallenge could skip straight to
+// SaveConf/DeleteConf/etc and handle_msg would still act on it.
+fn requires_client_auth(data: &ZatelIpcData) -> bool {
+    !matches!(
+        data,
+        ZatelIpcData::QueryProtocolVersion
+            | ZatelIpcData::AuthChallenge(_)
+            | ZatelIpcData::AuthResponse { .. }
+            | ZatelIpcData::QueryPluginInfo
+    )
+}
+
+// The daemon authenticates us on every connection (see authenticate_plugin
+// in this module), but a plugin socket is reachable by any local process -
+// an abstract-namespace name is not a secret - so prove the peer on this
+// connection really is our daemon before trusting anything past the
+// generic protocol handshake above: send a fresh nonce and check the
+// signature the client returns against the single daemon public key this
+// plugin was spawned with (see run_plugin).
+async fn challenge_client(
+    ipc_stream: &mut ZatelIpcStream<LocalSocketStream>,
+    trusted_daemon_key: &PublicKey,
+    plugin_name: &str,
+) -> Result<(), ZatelError> {
+    let nonce = gen_auth_nonce();
+    match timeout(
+        HANDSHAKE_TIMEOUT,
+        ipc_send(
+            ipc_stream,
+            &ZatelIpcMessage::new(ZatelIpcData::AuthChallenge(nonce)),
+        ),
+    )
+    .await
+    {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "{}: failed to send AuthChallenge to client within {:?}",
+                plugin_name, HANDSHAKE_TIMEOUT
+            )))
+        }
+    }
+
+    let reply = match timeout(HANDSHAKE_TIMEOUT, ipc_recv(ipc_stream)).await {
+        Ok(r) => r?,
+        Err(_) => {
+            return Err(ZatelError::timeout(format!(
+                "{}: client did not answer our AuthChallenge within {:?}",
+                plugin_name, HANDSHAKE_TIMEOUT
+            )))
+        }
+    };
+    let (pubkey, signature) = match reply.data {
+        ZatelIpcData::AuthResponse { pubkey, signature } => {
+            (pubkey, signature)
+        }
+        _ => {
+            return Err(ZatelError::plugin_error(format!(
+                "{}: client did not reply with AuthResponse: {:?}",
+                plugin_name, reply
+            )))
+        }
+    };
+
+    verify_auth_response(&[*trusted_daemon_key], &nonce, &pubkey, &signature)
+        .map_err(|e| {
+            ZatelError::plugin_error(format!(
+                "{}: client failed authentication: {}",
+                plugin_name, e
+            ))
+        })
+}
+
+async fn handle_msg<P: ZatelPlugin + ?Sized>(
+    plugin: &P,
+    data: ZatelIpcData,
+    format: ZatelWireFormat,
+    identity: &Keypair,
+) -> ZatelIpcMessage {
+    debug!("{}: Got request: {:?}", plugin.name(), data);
+    match data {
+        ZatelIpcData::QueryProtocolVersion => ZatelIpcMessage::new(
+            ZatelIpcData::QueryProtocolVersionReply(ZATEL_PROTOCOL_VERSION),
+        ),
+        ZatelIpcData::AuthChallenge(nonce) => {
+            let (pubkey, signature) = sign_auth_nonce(identity, &nonce);
+            ZatelIpcMessage::new(ZatelIpcData::AuthResponse {
+                pubkey,
+                signature,
+            })
+        }
+        ZatelIpcData::QueryPluginInfo => {
+            let mut info = plugin.query_plugin_info().await;
+            info.pubkey = identity.public.to_bytes();
+            ZatelIpcMessage::new(ZatelIpcData::QueryPluginInfoReply(info))
+        }
+        ZatelIpcData::QueryIfaceInfo(iface_name) => ZatelIpcMessage::from_result(
+            plugin.query_iface(&iface_name, format).await,
+        ),
+        ZatelIpcData::ValidateConf(conf) => {
+            ZatelIpcMessage::from_result(plugin.validate_conf(&conf).await)
+        }
+        ZatelIpcData::SaveConf(ztl_con) => {
+            ZatelIpcMessage::from_result(plugin.save_conf(ztl_con).await)
+        }
+        ZatelIpcData::QuerySavedConf(uuid) => ZatelIpcMessage::from_result(
+            plugin.query_saved_conf(&uuid).await,
+        ),
+        ZatelIpcData::QuerySavedConfAll => {
+            ZatelIpcMessage::from_result(plugin.query_saved_conf_all().await)
+        }
+        ZatelIpcData::DeleteConf(uuid) => {
+            ZatelIpcMessage::from_result(plugin.delete_conf(&uuid).await)
+        }
+        ZatelIpcData::DeactivateConf(uuid) => ZatelIpcMessage::from_result(
+            plugin.deactivate_conf(&uuid).await,
+        ),
+        ZatelIpcData::QueryFirewallRules => ZatelIpcMessage::from_result(
+            plugin.query_firewall_rules().await,
+        ),
+        ZatelIpcData::ValidateFirewallRules(rules) => {
+            ZatelIpcMessage::from_result(
+                plugin.validate_firewall_rules(&rules).await,
+            )
+        }
+        ZatelIpcData::ApplyFirewallRules(rules) => ZatelIpcMessage::from_result(
+            plugin.apply_firewall_rules(&rules).await,
+        ),
+        _ => {
+            warn!("{}: Got unknown request: {:?}", plugin.name(), &data);
+            ZatelIpcMessage::new(ZatelIpcData::None)
+        }
+    }
 }
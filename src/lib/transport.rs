@@ -0,0 +1,437 @@
+//    Copyright 2021 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Lets the daemon's client-facing listener (and ztl's connection to it)
+// run over something other than the default Unix local socket, so the
+// daemon can be administered from another host. Plugin connections are
+// unaffected -- plugins always run on the same host as the daemon and
+// keep using the Unix socket helpers in crate::ipc directly.
+//
+// Unlike the Unix socket, which restricts access via filesystem
+// permissions, `tls://` requires every client to present a certificate
+// signed by a CA the daemon trusts (see ZATEL_TLS_CLIENT_CA below) -- the
+// TLS handshake is the access control for remote administration.
+// `tcp://` has no equivalent and should only be pointed at a trusted
+// network (e.g. a loopback forward or a VPN); it exists for testing and
+// for deployments that terminate TLS elsewhere.
+
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use interprocess::local_socket::tokio::{LocalSocketListener, LocalSocketStream};
+use log::warn;
+use rustls_native_certs;
+use rustls_pemfile;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::{rustls, TlsAcceptor, TlsConnector, TlsStream};
+
+use crate::ipc::{
+    ipc_bind_with_path, ipc_unbind_with_path, to_local_socket_name,
+};
+use crate::ZatelError;
+
+const ZATEL_SERVER_ENV: &str = "ZATEL_SERVER";
+const ZATEL_TLS_CERT_ENV: &str = "ZATEL_TLS_CERT";
+const ZATEL_TLS_KEY_ENV: &str = "ZATEL_TLS_KEY";
+const ZATEL_TLS_CLIENT_CA_ENV: &str = "ZATEL_TLS_CLIENT_CA";
+const ZATEL_TLS_CLIENT_CERT_ENV: &str = "ZATEL_TLS_CLIENT_CERT";
+const ZATEL_TLS_CLIENT_KEY_ENV: &str = "ZATEL_TLS_CLIENT_KEY";
+
+// Where the daemon's client-facing listener should bind (and where ztl
+// should connect to), as chosen by ZATEL_SERVER/`ztl --server`. Carries
+// the original host string (not a resolved SocketAddr) for Tcp/Tls so TLS
+// certificate verification has a hostname to check against.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZatelServerAddr {
+    Unix(String),
+    Tcp(String),
+    Tls(String),
+}
+
+impl ZatelServerAddr {
+    // Accepts "tcp://host:port", "tls://host:port", or anything else as a
+    // Unix socket path/name (same syntax ipc_bind_with_path/
+    // ipc_connect_with_path already take).
+    pub fn parse(s: &str) -> Result<Self, ZatelError> {
+        if let Some(host_port) = s.strip_prefix("tcp://") {
+            Ok(ZatelServerAddr::Tcp(host_port.to_string()))
+        } else if let Some(host_port) = s.strip_prefix("tls://") {
+            Ok(ZatelServerAddr::Tls(host_port.to_string()))
+        } else {
+            Ok(ZatelServerAddr::Unix(s.to_string()))
+        }
+    }
+}
+
+// The transport the daemon binds to (and ztl connects to) when neither
+// ZATEL_SERVER nor --server says otherwise.
+pub fn default_server_addr() -> ZatelServerAddr {
+    ZatelServerAddr::Unix(crate::ipc::DEFAULT_SOCKET_PATH.to_string())
+}
+
+// Parse ZATEL_SERVER (same syntax as `ztl --server`), falling back to
+// default_server_addr() when unset or invalid.
+pub fn server_addr_from_env() -> ZatelServerAddr {
+    let default = default_server_addr();
+    match std::env::var(ZATEL_SERVER_ENV) {
+        Ok(s) => match ZatelServerAddr::parse(&s) {
+            Ok(a) => a,
+            Err(e) => {
+                warn!(
+                    "Invalid {}={:?}: {}, falling back to {:?}",
+                    ZATEL_SERVER_ENV, s, e, default
+                );
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+// A connected IPC byte stream, regardless of which transport produced it.
+// Boxing the transport behind this enum is what lets ipc_send/ipc_recv/
+// handle_client stay transport-agnostic: they only ever need `S: AsyncRead
+// + AsyncWrite + Unpin`, which ZatelStream implements by delegating to
+// whichever variant is actually in use.
+pub enum ZatelStream {
+    Unix(LocalSocketStream),
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ZatelStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ZatelStream::Unix(s) => Pin::new(s).poll_read(cx, buf),
+            ZatelStream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            ZatelStream::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ZatelStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ZatelStream::Unix(s) => Pin::new(s).poll_write(cx, buf),
+            ZatelStream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            ZatelStream::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ZatelStream::Unix(s) => Pin::new(s).poll_flush(cx),
+            ZatelStream::Tcp(s) => Pin::new(s).poll_flush(cx),
+            ZatelStream::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ZatelStream::Unix(s) => Pin::new(s).poll_shutdown(cx),
+            ZatelStream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            ZatelStream::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+// The daemon's client-facing listener, bound to whichever transport
+// ZatelServerAddr selected.
+pub enum ZatelListener {
+    Unix(LocalSocketListener),
+    Tcp(TcpListener),
+    Tls(TcpListener, TlsAcceptor),
+}
+
+impl ZatelListener {
+    pub async fn accept(&self) -> Result<ZatelStream, ZatelError> {
+        match self {
+            ZatelListener::Unix(l) => {
+                let s = l.accept().await.map_err(|e| {
+                    ZatelError::bug(format!(
+                        "Failed to accept Unix connection: {}",
+                        e
+                    ))
+                })?;
+                Ok(ZatelStream::Unix(s))
+            }
+            ZatelListener::Tcp(l) => {
+                let (s, _addr) = l.accept().await.map_err(|e| {
+                    ZatelError::bug(format!(
+                        "Failed to accept TCP connection: {}",
+                        e
+                    ))
+                })?;
+                Ok(ZatelStream::Tcp(s))
+            }
+            ZatelListener::Tls(l, acceptor) => {
+                let (s, _addr) = l.accept().await.map_err(|e| {
+                    ZatelError::bug(format!(
+                        "Failed to accept TCP connection: {}",
+                        e
+                    ))
+                })?;
+                let tls_stream = acceptor.accept(s).await.map_err(|e| {
+                    ZatelError::bug(format!("TLS handshake failed: {}", e))
+                })?;
+                Ok(ZatelStream::Tls(Box::new(TlsStream::Server(tls_stream))))
+            }
+        }
+    }
+}
+
+// Bind the daemon's client-facing listener on `addr`. Called once at
+// daemon start-up, mirroring how ipc_bind() was used before this existed.
+pub async fn bind_transport(
+    addr: &ZatelServerAddr,
+) -> Result<ZatelListener, ZatelError> {
+    match addr {
+        ZatelServerAddr::Unix(path) => {
+            Ok(ZatelListener::Unix(ipc_bind_with_path(path)?))
+        }
+        ZatelServerAddr::Tcp(host_port) => {
+            Ok(ZatelListener::Tcp(bind_tcp(host_port).await?))
+        }
+        ZatelServerAddr::Tls(host_port) => {
+            let listener = bind_tcp(host_port).await?;
+            let acceptor = build_tls_acceptor()?;
+            Ok(ZatelListener::Tls(listener, acceptor))
+        }
+    }
+}
+
+// Clean up whatever bind_transport(addr) left behind. Only the Unix
+// variant needs this (a stale socket file); Tcp/Tls listeners are
+// reclaimed by the OS when the listening socket closes.
+pub fn unbind_transport(addr: &ZatelServerAddr) {
+    if let ZatelServerAddr::Unix(path) = addr {
+        ipc_unbind_with_path(path);
+    }
+}
+
+// Connect to a daemon listening on `addr`. Used by ztl --server, in place
+// of ipc_connect()/ipc_connect_with_path() for the Unix-only default.
+pub async fn connect_transport(
+    addr: &ZatelServerAddr,
+) -> Result<ZatelStream, ZatelError> {
+    match addr {
+        ZatelServerAddr::Unix(path) => {
+            let name = to_local_socket_name(path)?;
+            let s = LocalSocketStream::connect(name).await.map_err(|e| {
+                ZatelError::bug(format!(
+                    "Failed to connect socket {}: {}",
+                    path, e
+                ))
+            })?;
+            Ok(ZatelStream::Unix(s))
+        }
+        ZatelServerAddr::Tcp(host_port) => {
+            Ok(ZatelStream::Tcp(connect_tcp(host_port).await?))
+        }
+        ZatelServerAddr::Tls(host_port) => {
+            let tcp_stream = connect_tcp(host_port).await?;
+            let host = host_of(host_port)?;
+            let connector = build_tls_connector()?;
+            let server_name =
+                rustls::ServerName::try_from(host.as_str()).map_err(|e| {
+                    ZatelError::invalid_argument(format!(
+                        "Invalid TLS server name {:?}: {}",
+                        host, e
+                    ))
+                })?;
+            let tls_stream = connector
+                .connect(server_name, tcp_stream)
+                .await
+                .map_err(|e| {
+                    ZatelError::bug(format!("TLS handshake failed: {}", e))
+                })?;
+            Ok(ZatelStream::Tls(Box::new(TlsStream::Client(tls_stream))))
+        }
+    }
+}
+
+async fn bind_tcp(host_port: &str) -> Result<TcpListener, ZatelError> {
+    TcpListener::bind(host_port).await.map_err(|e| {
+        ZatelError::bug(format!("Failed to bind TCP {}: {}", host_port, e))
+    })
+}
+
+async fn connect_tcp(host_port: &str) -> Result<TcpStream, ZatelError> {
+    TcpStream::connect(host_port).await.map_err(|e| {
+        ZatelError::bug(format!(
+            "Failed to connect TCP {}: {}",
+            host_port, e
+        ))
+    })
+}
+
+fn host_of(host_port: &str) -> Result<String, ZatelError> {
+    host_port
+        .rsplit_once(':')
+        .map(|(host, _port)| host.to_string())
+        .ok_or_else(|| {
+            ZatelError::invalid_argument(format!(
+                "Invalid TCP address {:?}, expected host:port",
+                host_port
+            ))
+        })
+}
+
+// Load the server certificate and private key ZATEL_TLS_CERT/
+// ZATEL_TLS_KEY point at, and the CA ZATEL_TLS_CLIENT_CA point at, and
+// build a rustls server config that requires every connecting client to
+// present a certificate signed by that CA. All three variables are
+// required once `tls://` is selected -- there is no sensible default
+// certificate/CA to fall back to, and a daemon exposed to the network
+// with no client authentication would let anyone who can reach the port
+// administer it.
+fn build_tls_acceptor() -> Result<TlsAcceptor, ZatelError> {
+    let cert_path = require_env(ZATEL_TLS_CERT_ENV)?;
+    let key_path = require_env(ZATEL_TLS_KEY_ENV)?;
+    let client_ca_path = require_env(ZATEL_TLS_CLIENT_CA_ENV)?;
+
+    let certs = load_certs(&cert_path)?;
+    let key = load_private_key(&key_path)?;
+    let client_ca_certs = load_certs(&client_ca_path)?;
+
+    let mut client_ca_roots = rustls::RootCertStore::empty();
+    for cert in client_ca_certs {
+        client_ca_roots.add(&cert).map_err(|e| {
+            ZatelError::invalid_argument(format!(
+                "Invalid TLS client CA certificate {}: {}",
+                client_ca_path, e
+            ))
+        })?;
+    }
+    let client_cert_verifier =
+        rustls::server::AllowAnyAuthenticatedClient::new(client_ca_roots);
+
+    let config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_cert_verifier)
+        .with_single_cert(certs, key)
+        .map_err(|e| {
+            ZatelError::bug(format!("Invalid TLS certificate/key: {}", e))
+        })?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Build a rustls client config trusting the platform's native root CA
+// store for the server's certificate (the same set of authorities a
+// browser on this host would trust), and presenting the client
+// certificate/key ZATEL_TLS_CLIENT_CERT/ZATEL_TLS_CLIENT_KEY point at so
+// the daemon's AllowAnyAuthenticatedClient check above can authenticate
+// this client.
+fn build_tls_connector() -> Result<TlsConnector, ZatelError> {
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in rustls_native_certs::load_native_certs().map_err(|e| {
+        ZatelError::bug(format!("Failed to load native TLS roots: {}", e))
+    })? {
+        roots
+            .add(&rustls::Certificate(cert.0))
+            .map_err(|e| {
+                ZatelError::bug(format!("Invalid native root cert: {}", e))
+            })?;
+    }
+
+    let client_cert_path = require_env(ZATEL_TLS_CLIENT_CERT_ENV)?;
+    let client_key_path = require_env(ZATEL_TLS_CLIENT_KEY_ENV)?;
+    let client_certs = load_certs(&client_cert_path)?;
+    let client_key = load_private_key(&client_key_path)?;
+
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(client_certs, client_key)
+        .map_err(|e| {
+            ZatelError::bug(format!(
+                "Invalid TLS client certificate/key: {}",
+                e
+            ))
+        })?;
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn require_env(var_name: &str) -> Result<String, ZatelError> {
+    std::env::var(var_name).map_err(|_| {
+        ZatelError::invalid_argument(format!(
+            "{} must be set to use the tls:// transport",
+            var_name
+        ))
+    })
+}
+
+fn load_certs(
+    path: &str,
+) -> Result<Vec<rustls::Certificate>, ZatelError> {
+    let f = std::fs::File::open(path).map_err(|e| {
+        ZatelError::invalid_argument(format!(
+            "Failed to open TLS certificate {}: {}",
+            path, e
+        ))
+    })?;
+    let mut reader = io::BufReader::new(f);
+    rustls_pemfile::certs(&mut reader)
+        .map_err(|e| {
+            ZatelError::invalid_argument(format!(
+                "Invalid TLS certificate {}: {}",
+                path, e
+            ))
+        })
+        .map(|certs| certs.into_iter().map(rustls::Certificate).collect())
+}
+
+fn load_private_key(
+    path: &str,
+) -> Result<rustls::PrivateKey, ZatelError> {
+    let f = std::fs::File::open(path).map_err(|e| {
+        ZatelError::invalid_argument(format!(
+            "Failed to open TLS private key {}: {}",
+            path, e
+        ))
+    })?;
+    let mut reader = io::BufReader::new(f);
+    let keys = rustls_pemfile::pkcs8_private_keys(&mut reader).map_err(|e| {
+        ZatelError::invalid_argument(format!(
+            "Invalid TLS private key {}: {}",
+            path, e
+        ))
+    })?;
+    keys.into_iter()
+        .next()
+        .map(rustls::PrivateKey)
+        .ok_or_else(|| {
+            ZatelError::invalid_argument(format!(
+                "No private key found in {}",
+                path
+            ))
+        })
+}
@@ -65,6 +65,336 @@ pub fn merge_yaml_mappings(yml_strs: &[&str]) -> Result<String, ZatelError> {
     }
 }
 
+// Like `merge_yaml_mappings`, but descends into nested mappings and
+// sequences instead of only merging top-level keys. This lets two plugins
+// each contribute disjoint sub-fields of the same nested interface (e.g.
+// one plugin supplies `interfaces[0].ipv4`, another `interfaces[0].ipv6`)
+// without colliding on the shared `interfaces[0]` parent the way the flat
+// merge would. Mappings are merged key-by-key; sequences are merged
+// index-by-index so two plugins describing the same list entry combine
+// rather than duplicate it. A scalar that differs between replies is
+// still a hard error, same as `merge_yaml_mappings`.
+pub fn merge_yaml_mappings_recursive(
+    yml_strs: &[&str],
+) -> Result<String, ZatelError> {
+    let mut full_value = serde_yaml::Value::Null;
+
+    for yml_str in yml_strs {
+        let cur_value: serde_yaml::Value = match serde_yaml::from_str(yml_str)
+        {
+            Ok(i) => i,
+            Err(e) => {
+                return Err(ZatelError::plugin_error(format!(
+                    "Invalid format of YAML reply from plugin: {}, {}",
+                    yml_str, e
+                )))
+            }
+        };
+        full_value = merge_yaml_values("", full_value, cur_value)?;
+    }
+
+    match serde_yaml::to_string(&full_value) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(ZatelError::bug(format!(
+            "This should never happen: \
+            Failed to convert serde_yaml::Value to string: {:?} {}",
+            &full_value, e
+        ))),
+    }
+}
+
+fn merge_yaml_values(
+    path: &str,
+    base: serde_yaml::Value,
+    overlay: serde_yaml::Value,
+) -> Result<serde_yaml::Value, ZatelError> {
+    match (base, overlay) {
+        (serde_yaml::Value::Null, v) => Ok(v),
+        (v, serde_yaml::Value::Null) => Ok(v),
+        (
+            serde_yaml::Value::Mapping(mut base_map),
+            serde_yaml::Value::Mapping(overlay_map),
+        ) => {
+            for (key, value) in overlay_map {
+                let child_path = join_yaml_path(path, &yaml_key_str(&key));
+                let merged = match base_map.remove(&key) {
+                    Some(base_value) => {
+                        merge_yaml_values(&child_path, base_value, value)?
+                    }
+                    None => value,
+                };
+                base_map.insert(key, merged);
+            }
+            Ok(serde_yaml::Value::Mapping(base_map))
+        }
+        (
+            serde_yaml::Value::Sequence(mut base_seq),
+            serde_yaml::Value::Sequence(overlay_seq),
+        ) => {
+            for (i, value) in overlay_seq.into_iter().enumerate() {
+                let child_path = format!("{}[{}]", path, i);
+                match base_seq.get(i).cloned() {
+                    Some(base_value) => {
+                        base_seq[i] =
+                            merge_yaml_values(&child_path, base_value, value)?
+                    }
+                    None => base_seq.push(value),
+                }
+            }
+            Ok(serde_yaml::Value::Sequence(base_seq))
+        }
+        (base_value, overlay_value) => {
+            if base_value == overlay_value {
+                Ok(base_value)
+            } else {
+                Err(ZatelError::plugin_error(format!(
+                    "Duplicate key: {}: new: {:?}, old: {:?}",
+                    path, overlay_value, base_value
+                )))
+            }
+        }
+    }
+}
+
+// Walk `desired` and `actual` side by side and collect a path-qualified
+// report of every place they diverge, so a caller whose plugins can't
+// fully satisfy a desired config can tell the user exactly which field
+// failed (e.g. "interfaces[0].ipv4.address: desired ... not satisfied by
+// plugins") instead of dumping both full YAML documents for them to diff
+// by eye.
+pub fn yaml_diff(
+    desired: &serde_yaml::Value,
+    actual: &serde_yaml::Value,
+) -> Vec<String> {
+    let mut diffs = Vec::new();
+    yaml_diff_at("", desired, actual, &mut diffs);
+    diffs
+}
+
+fn yaml_diff_at(
+    path: &str,
+    desired: &serde_yaml::Value,
+    actual: &serde_yaml::Value,
+    diffs: &mut Vec<String>,
+) {
+    match (desired, actual) {
+        (
+            serde_yaml::Value::Mapping(desired_map),
+            serde_yaml::Value::Mapping(actual_map),
+        ) => {
+            for (key, desired_value) in desired_map.iter() {
+                let child_path = join_yaml_path(path, &yaml_key_str(key));
+                match actual_map.get(key) {
+                    Some(actual_value) => yaml_diff_at(
+                        &child_path,
+                        desired_value,
+                        actual_value,
+                        diffs,
+                    ),
+                    None => diffs.push(format!(
+                        "{}: desired {} not satisfied by plugins",
+                        child_path,
+                        yaml_scalar_str(desired_value)
+                    )),
+                }
+            }
+            for (key, actual_value) in actual_map.iter() {
+                if !desired_map.contains_key(key) {
+                    let child_path = join_yaml_path(path, &yaml_key_str(key));
+                    diffs.push(format!(
+                        "{}: {} reported by plugins but not desired",
+                        child_path,
+                        yaml_scalar_str(actual_value)
+                    ));
+                }
+            }
+        }
+        (
+            serde_yaml::Value::Sequence(desired_seq),
+            serde_yaml::Value::Sequence(actual_seq),
+        ) => {
+            for (i, desired_value) in desired_seq.iter().enumerate() {
+                let child_path = format!("{}[{}]", path, i);
+                match actual_seq.get(i) {
+                    Some(actual_value) => yaml_diff_at(
+                        &child_path,
+                        desired_value,
+                        actual_value,
+                        diffs,
+                    ),
+                    None => diffs.push(format!(
+                        "{}: desired {} not satisfied by plugins",
+                        child_path,
+                        yaml_scalar_str(desired_value)
+                    )),
+                }
+            }
+            for (i, actual_value) in
+                actual_seq.iter().enumerate().skip(desired_seq.len())
+            {
+                let child_path = format!("{}[{}]", path, i);
+                diffs.push(format!(
+                    "{}: {} reported by plugins but not desired",
+                    child_path,
+                    yaml_scalar_str(actual_value)
+                ));
+            }
+        }
+        (desired_value, actual_value) => {
+            if desired_value != actual_value {
+                diffs.push(format!(
+                    "{}: desired {} not satisfied by plugins",
+                    path,
+                    yaml_scalar_str(desired_value)
+                ));
+            }
+        }
+    }
+}
+
+fn join_yaml_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn yaml_key_str(key: &serde_yaml::Value) -> String {
+    match key {
+        serde_yaml::Value::String(s) => s.clone(),
+        other => yaml_scalar_str(other),
+    }
+}
+
+fn yaml_scalar_str(value: &serde_yaml::Value) -> String {
+    match value {
+        serde_yaml::Value::String(s) => format!("{:?}", s),
+        serde_yaml::Value::Null => "null".to_string(),
+        serde_yaml::Value::Bool(b) => b.to_string(),
+        serde_yaml::Value::Number(n) => n.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+// Three-way merge over flat YAML mappings, same top-level-keys-only scope
+// as `merge_yaml_mappings`: a key that differs between `base` (the config
+// last applied) and `desired` (the new target) is treated as a change the
+// user actually asked for and wins, including a key `desired` dropped
+// entirely -- that is read as the user deleting an override, so it is
+// dropped from the result rather than carried over from `current`. A key
+// the user left untouched keeps whatever `current` (the live runtime
+// state) already holds, so reconciling a config never clobbers runtime
+// drift nobody asked to change. Returns the fully merged target config
+// plus the subset of keys that still differ from `current` -- the delta a
+// caller actually needs to apply.
+pub fn merge_yaml_mappings_three_way(
+    base: &str,
+    current: &str,
+    desired: &str,
+) -> Result<(String, String), ZatelError> {
+    let base_map = parse_yaml_mapping(base)?;
+    let current_map = parse_yaml_mapping(current)?;
+    let desired_map = parse_yaml_mapping(desired)?;
+
+    let mut merged = serde_yaml::Mapping::new();
+    let mut delta = serde_yaml::Mapping::new();
+
+    let mut keys: Vec<&serde_yaml::Value> = base_map.keys().collect();
+    for key in desired_map.keys() {
+        if !base_map.contains_key(key) {
+            keys.push(key);
+        }
+    }
+    for key in current_map.keys() {
+        if !base_map.contains_key(key) && !desired_map.contains_key(key) {
+            keys.push(key);
+        }
+    }
+
+    for key in keys {
+        let desired_value = desired_map.get(key);
+        let base_value = base_map.get(key);
+        let current_value = current_map.get(key);
+
+        let final_value = match desired_value {
+            Some(v) if desired_value != base_value => v.clone(),
+            Some(v) => current_value.unwrap_or(v).clone(),
+            None if base_value.is_some() => {
+                // Present in the base but dropped from desired: the user
+                // deleted this override, so do not resurrect it from
+                // `current`.
+                continue;
+            }
+            None => match current_value {
+                Some(v) => v.clone(),
+                None => continue,
+            },
+        };
+
+        if current_value != Some(&final_value) {
+            delta.insert(key.clone(), final_value.clone());
+        }
+        merged.insert(key.clone(), final_value);
+    }
+
+    Ok((yaml_mapping_to_string(&merged)?, yaml_mapping_to_string(&delta)?))
+}
+
+// True when every top-level key `desired` holds is present in `current`
+// with the same value; used to check whether a runtime has converged to a
+// config that was just applied. Extra keys `current` carries that
+// `desired` doesn't mention are ignored, mirroring the "only track what we
+// asked for" rule `merge_yaml_mappings_three_way` follows.
+pub fn yaml_mapping_converged(
+    desired: &str,
+    current: &str,
+) -> Result<bool, ZatelError> {
+    let desired_map = parse_yaml_mapping(desired)?;
+    let current_map = parse_yaml_mapping(current)?;
+
+    for (key, value) in desired_map.iter() {
+        if current_map.get(key) != Some(value) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+fn parse_yaml_mapping(
+    yml_str: &str,
+) -> Result<serde_yaml::Mapping, ZatelError> {
+    let value: serde_yaml::Value = match serde_yaml::from_str(yml_str) {
+        Ok(i) => i,
+        Err(e) => {
+            return Err(ZatelError::invalid_argument(format!(
+                "Invalid format of YAML: {}",
+                e
+            )))
+        }
+    };
+    match value.as_mapping() {
+        Some(m) => Ok(m.clone()),
+        None => Err(ZatelError::invalid_argument(format!(
+            "WARN: {:?} is not mapping",
+            value
+        ))),
+    }
+}
+
+fn yaml_mapping_to_string(
+    obj: &serde_yaml::Mapping,
+) -> Result<String, ZatelError> {
+    match serde_yaml::to_string(obj) {
+        Ok(s) => Ok(s),
+        Err(e) => Err(ZatelError::bug(format!(
+            "This should never happen: \
+            Failed to convert serde_yaml::Mapping to string: {:?} {}",
+            obj, e
+        ))),
+    }
+}
+
 pub fn merge_yaml_lists(yml_strs: &[&str]) -> Result<String, ZatelError> {
     let mut full_obj = serde_yaml::Sequence::new();
 
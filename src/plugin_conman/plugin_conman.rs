@@ -16,119 +16,98 @@ use std::env::args;
 use std::fs::OpenOptions;
 use std::io::{Read, Write};
 
+use async_trait::async_trait;
+use log::error;
 use serde_yaml;
-use tokio::{self, io::AsyncWriteExt, net::UnixStream};
 use zatel::{
-    ipc_bind_with_path, ipc_recv, ipc_send, ZatelConnection, ZatelError,
-    ZatelIpcData, ZatelIpcMessage, ZatelPluginCapacity, ZatelPluginInfo,
+    init_logging, run_plugin, ZatelConnection, ZatelError, ZatelIpcData,
+    ZatelIpcMessage, ZatelPlugin, ZatelPluginCapacity,
 };
 
 const PLUGIN_NAME: &str = "conman";
+const PLUGIN_IDENTITY_PATH: &str = "/etc/zatel/plugins/conman.key";
 const CONF_FOLDER: &str = "/tmp/zatel";
 const CONN_FILE_POSTFIX: &str = ".yml";
 
 const CONNECTION_KEY: &str = "_connection";
 
-#[tokio::main()]
-async fn main() {
-    let argv: Vec<String> = args().collect();
+struct ConmanPlugin;
 
-    if argv.len() != 2 {
-        eprintln!(
-            "Invalid argument, should be single argument: <plugin_socket_path>"
-        );
-        std::process::exit(1);
+#[async_trait]
+impl ZatelPlugin for ConmanPlugin {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
     }
 
-    if let Err(e) = create_conf_dir() {
-        eprintln!(
-            "Failed to create folder for saving configurations {}: {}",
-            CONF_FOLDER, e
-        );
-        std::process::exit(1);
+    fn identity_path(&self) -> &str {
+        PLUGIN_IDENTITY_PATH
     }
 
-    let socket_path = &argv[1];
+    fn capacities(&self) -> Vec<ZatelPluginCapacity> {
+        vec![ZatelPluginCapacity::Config]
+    }
 
-    let listener = match ipc_bind_with_path(socket_path) {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
-        }
-    };
-    eprintln!("DEBUG: {}: listening on {}", PLUGIN_NAME, socket_path);
-
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                // TODO: Limit the maximum connected client as it could
-                //       from suspicious source, not daemon
-                tokio::task::spawn(async move { handle_client(stream).await });
-            }
-            Err(e) => {
-                eprintln!("{}", e);
-            }
-        }
+    async fn save_conf(
+        &self,
+        ztl_con: ZatelConnection,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        save_conf(ztl_con)
+    }
+
+    async fn query_saved_conf(
+        &self,
+        uuid: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        query(uuid)
+    }
+
+    async fn query_saved_conf_all(
+        &self,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        query_all()
+    }
+
+    async fn delete_conf(
+        &self,
+        uuid: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        delete_conf(uuid)
+    }
+
+    async fn deactivate_conf(
+        &self,
+        uuid: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        deactivate_conf(uuid)
     }
 }
 
-async fn shutdown_connection(stream: &mut UnixStream) {
-    if let Err(e) = stream.shutdown().await {
+#[tokio::main()]
+async fn main() {
+    if let Err(e) = init_logging("zatel_plugin_conman") {
         eprintln!("{}", e);
+        std::process::exit(1);
     }
-}
 
-// TODO: Implement on:
-//  * timeout
-async fn handle_client(mut stream: UnixStream) {
-    loop {
-        match ipc_recv(&mut stream).await {
-            Ok(ipc_msg) => match ipc_msg.data {
-                ZatelIpcData::ConnectionClosed => {
-                    shutdown_connection(&mut stream).await;
-                    break;
-                }
-                _ => {
-                    let message = handle_msg(ipc_msg.data).await;
-                    eprintln!("DEBUG: {}: reply: {:?}", PLUGIN_NAME, &message);
-                    if let Err(e) = ipc_send(&mut stream, &message).await {
-                        eprintln!(
-                            "DEBUG: {}: failed to send to daemon : {}",
-                            PLUGIN_NAME, e
-                        );
-                    }
-                }
-            },
-            Err(e) => {
-                eprintln!("IPC error {}", e);
-                shutdown_connection(&mut stream).await;
-                break;
-            }
-        }
+    let argv: Vec<String> = args().collect();
+
+    if argv.len() != 3 {
+        error!(
+            "Invalid arguments, should be: <plugin_socket_path> \
+            <daemon_pubkey_hex>"
+        );
+        std::process::exit(1);
     }
-}
 
-async fn handle_msg(data: ZatelIpcData) -> ZatelIpcMessage {
-    eprintln!("DEBUG: {}: Got request: {:?}", PLUGIN_NAME, data);
-    match data {
-        ZatelIpcData::QueryPluginInfo => ZatelIpcMessage::new(
-            ZatelIpcData::QueryPluginInfoReply(ZatelPluginInfo::new(
-                PLUGIN_NAME,
-                vec![ZatelPluginCapacity::Config],
-            )),
-        ),
-        ZatelIpcData::SaveConf(ztl_con) => {
-            ZatelIpcMessage::from_result(save_conf(ztl_con))
-        }
-        ZatelIpcData::QuerySavedConf(uuid) => {
-            ZatelIpcMessage::from_result(query(&uuid))
-        }
-        ZatelIpcData::QuerySavedConfAll => {
-            ZatelIpcMessage::from_result(query_all())
-        }
-        _ => ZatelIpcMessage::new(ZatelIpcData::None),
+    if let Err(e) = create_conf_dir() {
+        error!(
+            "Failed to create folder for saving configurations {}: {}",
+            CONF_FOLDER, e
+        );
+        std::process::exit(1);
     }
+
+    run_plugin(ConmanPlugin, &argv[1], &argv[2]).await;
 }
 
 fn save_conf(ztl_con: ZatelConnection) -> Result<ZatelIpcMessage, ZatelError> {
@@ -187,15 +166,15 @@ fn query_all() -> Result<ZatelIpcMessage, ZatelError> {
                 let file_path = match entry {
                     Ok(f) => conf_dir_path.join(f.path()),
                     Err(e) => {
-                        eprintln!("FAIL: Failed to read dir entry: {}", e);
+                        error!("Failed to read dir entry: {}", e);
                         continue;
                     }
                 };
                 let file_path = match file_path.to_str() {
                     Some(f) => f,
                     None => {
-                        eprintln!(
-                            "BUG: Should never happen: \
+                        error!(
+                            "Should never happen: \
                         file_path.to_str() return None"
                         );
                         continue;
@@ -205,10 +184,7 @@ fn query_all() -> Result<ZatelIpcMessage, ZatelError> {
                 let conn_str = match read_file(file_path) {
                     Ok(s) => s,
                     Err(e) => {
-                        eprintln!(
-                            "ERROR: Failed to read file {}: {}",
-                            file_path, e
-                        );
+                        error!("Failed to read file {}: {}", file_path, e);
                         continue;
                     }
                 };
@@ -216,8 +192,8 @@ fn query_all() -> Result<ZatelIpcMessage, ZatelError> {
                     match zatel_connection_from_flat_string(&conn_str) {
                         Ok(c) => c,
                         Err(e) => {
-                            eprintln!(
-                                "ERROR: Invalid connection YAML file {}: {}",
+                            error!(
+                                "Invalid connection YAML file {}: {}",
                                 file_path, e
                             );
                             continue;
@@ -422,6 +398,30 @@ fn query(uuid: &str) -> Result<ZatelIpcMessage, ZatelError> {
     )))
 }
 
+fn delete_conf(uuid: &str) -> Result<ZatelIpcMessage, ZatelError> {
+    let file_path = gen_file_path(uuid);
+    if let Err(e) = std::fs::remove_file(&file_path) {
+        return Err(ZatelError::invalid_argument(format!(
+            "Failed to delete connection {}: {}",
+            uuid, e
+        )));
+    }
+    Ok(ZatelIpcMessage::new(ZatelIpcData::DeleteConfReply(
+        uuid.to_string(),
+    )))
+}
+
+// conman only persists desired config, it does not track live runtime
+// state, so "deactivating" a connection here just means acknowledging
+// the request with the saved config unchanged -- any plugin with Apply
+// capacity is responsible for actually tearing the interface down.
+fn deactivate_conf(uuid: &str) -> Result<ZatelIpcMessage, ZatelError> {
+    let conn_str = read_file(&gen_file_path(uuid))?;
+    Ok(ZatelIpcMessage::new(ZatelIpcData::DeactivateConfReply(
+        zatel_connection_from_flat_string(&conn_str)?,
+    )))
+}
+
 fn gen_file_path(uuid: &str) -> String {
     format!("{}/{}{}", CONF_FOLDER, uuid, CONN_FILE_POSTFIX)
 }
@@ -0,0 +1,180 @@
+//    Copyright 2021 Red Hat, Inc.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Reference implementation of the Firewall capacity: it does not
+// translate rules into nftables/iptables, it only persists the
+// declarative rule set verbatim and proves out the query/validate/apply
+// round trip the daemon expects from a Firewall-capacity plugin.
+
+use std::env::args;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+
+use async_trait::async_trait;
+use log::error;
+use serde_yaml;
+use zatel::{
+    init_logging, run_plugin, ZatelError, ZatelIpcData, ZatelIpcMessage,
+    ZatelPlugin, ZatelPluginCapacity,
+};
+
+const PLUGIN_NAME: &str = "firewall";
+const PLUGIN_IDENTITY_PATH: &str = "/etc/zatel/plugins/firewall.key";
+const RULES_FILE_PATH: &str = "/tmp/zatel/firewall_rules.yml";
+
+struct FirewallPlugin;
+
+#[async_trait]
+impl ZatelPlugin for FirewallPlugin {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
+    }
+
+    fn identity_path(&self) -> &str {
+        PLUGIN_IDENTITY_PATH
+    }
+
+    fn capacities(&self) -> Vec<ZatelPluginCapacity> {
+        vec![ZatelPluginCapacity::Firewall]
+    }
+
+    async fn query_firewall_rules(
+        &self,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        query_firewall_rules()
+    }
+
+    async fn validate_firewall_rules(
+        &self,
+        rules: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        validate_firewall_rules(rules)
+    }
+
+    async fn apply_firewall_rules(
+        &self,
+        rules: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        apply_firewall_rules(rules)
+    }
+}
+
+#[tokio::main()]
+async fn main() {
+    if let Err(e) = init_logging("zatel_plugin_firewall") {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let argv: Vec<String> = args().collect();
+
+    if argv.len() != 3 {
+        error!(
+            "Invalid arguments, should be: <plugin_socket_path> \
+            <daemon_pubkey_hex>"
+        );
+        std::process::exit(1);
+    }
+
+    run_plugin(FirewallPlugin, &argv[1], &argv[2]).await;
+}
+
+fn query_firewall_rules() -> Result<ZatelIpcMessage, ZatelError> {
+    let rules = read_rules_file().unwrap_or_else(|_| "{}\n".to_string());
+    Ok(ZatelIpcMessage::new(ZatelIpcData::QueryFirewallRulesReply(
+        rules,
+    )))
+}
+
+// Accepts any well-formed YAML mapping as the full rule set this plugin
+// can cover, mirroring how plugin_nispor's validate_conf echoes back a
+// round-tripped copy of what it was handed.
+fn validate_firewall_rules(
+    rules: &str,
+) -> Result<ZatelIpcMessage, ZatelError> {
+    let parsed: serde_yaml::Mapping = match serde_yaml::from_str(rules) {
+        Ok(m) => m,
+        Err(e) => {
+            return Err(ZatelError::invalid_argument(format!(
+                "Invalid firewall rules YAML: {}",
+                e
+            )));
+        }
+    };
+    match serde_yaml::to_string(&parsed) {
+        Ok(s) => Ok(ZatelIpcMessage::new(
+            ZatelIpcData::ValidateFirewallRulesReply(s),
+        )),
+        Err(e) => Err(ZatelError::bug(format!(
+            "This should never happen, failed to generate yaml string \
+            from Mapping: {:?}: {}",
+            &parsed, e
+        ))),
+    }
+}
+
+fn apply_firewall_rules(rules: &str) -> Result<ZatelIpcMessage, ZatelError> {
+    if let Some(parent) = std::path::Path::new(RULES_FILE_PATH).parent() {
+        if let Err(e) = std::fs::create_dir_all(parent) {
+            return Err(ZatelError::plugin_error(format!(
+                "Failed to create folder {:?}: {}",
+                parent, e
+            )));
+        }
+    }
+    let mut fd = match OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(RULES_FILE_PATH)
+    {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(ZatelError::plugin_error(format!(
+                "Failed to open file {}: {}",
+                RULES_FILE_PATH, e
+            )));
+        }
+    };
+    if let Err(e) = fd.write_all(rules.as_bytes()) {
+        return Err(ZatelError::plugin_error(format!(
+            "Failed to write file {}: {}",
+            RULES_FILE_PATH, e
+        )));
+    }
+    Ok(ZatelIpcMessage::new(ZatelIpcData::ApplyFirewallRulesReply(
+        rules.to_string(),
+    )))
+}
+
+fn read_rules_file() -> Result<String, ZatelError> {
+    let mut fd = match std::fs::File::open(RULES_FILE_PATH) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(ZatelError::plugin_error(format!(
+                "Failed to open {}: {}",
+                RULES_FILE_PATH, e
+            )))
+        }
+    };
+    let mut contents = String::new();
+    if let Err(e) = fd.read_to_string(&mut contents) {
+        Err(ZatelError::plugin_error(format!(
+            "Failed to read {}: {}",
+            RULES_FILE_PATH, e
+        )))
+    } else {
+        Ok(contents)
+    }
+}
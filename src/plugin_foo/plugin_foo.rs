@@ -14,16 +14,17 @@
 
 use std::env::args;
 
+use async_trait::async_trait;
+use log::error;
 use serde::{Deserialize, Serialize};
 use serde_yaml;
-use tokio::{self, io::AsyncWriteExt, net::UnixStream};
 use zatel::{
-    ipc_bind_with_path, ipc_connect, ipc_recv, ipc_send, ZatelError,
-    ZatelIpcData, ZatelIpcMessage,
+    init_logging, run_plugin, ZatelError, ZatelIpcData, ZatelIpcMessage,
+    ZatelPlugin, ZatelPluginCapacity, ZatelWireFormat,
 };
 
-
 const PLUGIN_NAME: &str = "foo";
+const PLUGIN_IDENTITY_PATH: &str = "/etc/zatel/plugins/foo.key";
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 struct FooInfo {
@@ -38,88 +39,49 @@ struct FooIface {
     foo: FooInfo,
 }
 
-#[tokio::main()]
-async fn main() {
-    let argv: Vec<String> = args().collect();
+struct FooPlugin;
 
-    if argv.len() != 2 {
-        eprintln!(
-            "Invalid argument, should be single argument: <plugin_socket_path>"
-        );
-        std::process::exit(1);
+#[async_trait]
+impl ZatelPlugin for FooPlugin {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
     }
 
-    let socket_path = &argv[1];
+    fn identity_path(&self) -> &str {
+        PLUGIN_IDENTITY_PATH
+    }
 
-    let listener = match ipc_bind_with_path(socket_path) {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
-        }
-    };
-    eprintln!("DEBUG: {}: listening on {}", PLUGIN_NAME, socket_path);
+    fn capacities(&self) -> Vec<ZatelPluginCapacity> {
+        vec![ZatelPluginCapacity::Query]
+    }
 
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                // TODO: Limit the maximum connected client as it could
-                //       from suspicious source, not daemon
-                tokio::task::spawn(async move { handle_client(stream).await });
-            }
-            Err(e) => {
-                eprintln!("{}", e);
-            }
-        }
+    async fn query_iface(
+        &self,
+        iface_name: &str,
+        _format: ZatelWireFormat,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        query_iface(iface_name)
     }
 }
 
-async fn shutdown_connection(stream: &mut UnixStream) {
-    if let Err(e) = stream.shutdown().await {
+#[tokio::main()]
+async fn main() {
+    if let Err(e) = init_logging("zatel_plugin_foo") {
         eprintln!("{}", e);
+        std::process::exit(1);
     }
-}
 
-// TODO: Implement on:
-//  * timeout
-async fn handle_client(mut stream: UnixStream) {
-    loop {
-        match ipc_recv(&mut stream).await {
-            Ok(ipc_msg) => match ipc_msg.data {
-                ZatelIpcData::ConnectionClosed => {
-                    shutdown_connection(&mut stream).await;
-                    break;
-                }
-                _ => {
-                    let message = handle_msg(&mut stream, ipc_msg.data).await;
-                    if let Err(e) = ipc_send(&mut stream, &message).await {
-                        eprintln!(
-                            "{}: failed to send to daemon : {}",
-                            PLUGIN_NAME, e
-                        );
-                    }
-                }
-            },
-            Err(e) => {
-                eprintln!("IPC error {}", e);
-                shutdown_connection(&mut stream).await;
-                break;
-            }
-        }
-    }
-}
+    let argv: Vec<String> = args().collect();
 
-async fn handle_msg(
-    stream: &mut UnixStream,
-    data: ZatelIpcData,
-) -> ZatelIpcMessage {
-    eprintln!("DEBUG: {}: Got request: {:?}", PLUGIN_NAME, data);
-    match data {
-        ZatelIpcData::QueryIfaceInfo(iface_name) => {
-            ZatelIpcMessage::from_result(query_iface(&iface_name))
-        }
-        _ => ZatelIpcMessage::new(ZatelIpcData::None),
+    if argv.len() != 3 {
+        error!(
+            "Invalid arguments, should be: <plugin_socket_path> \
+            <daemon_pubkey_hex>"
+        );
+        std::process::exit(1);
     }
+
+    run_plugin(FooPlugin, &argv[1], &argv[2]).await;
 }
 
 fn query_iface(iface_name: &str) -> Result<ZatelIpcMessage, ZatelError> {
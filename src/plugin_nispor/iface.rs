@@ -12,6 +12,7 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use log::error;
 use nispor::{Iface, IfaceState, IfaceType, Ipv4AddrInfo, Ipv6AddrInfo};
 use serde::{Deserialize, Serialize};
 
@@ -115,10 +116,7 @@ fn time_str_to_u32(time: &str) -> Option<u32> {
                         Some(i)
                     }
                     Err(e) => {
-                        eprintln!(
-                            "ERROR: invalid time string: {}: {}",
-                            time, e
-                        );
+                        error!("invalid time string: {}: {}", time, e);
                         Some(0u32)
                     }
                 }
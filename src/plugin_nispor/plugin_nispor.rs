@@ -16,119 +16,77 @@ mod iface;
 
 use std::env::args;
 
+use async_trait::async_trait;
+use log::error;
 use nispor::NetState;
+use serde_json;
 use serde_yaml;
-use tokio::{self, io::AsyncWriteExt, net::UnixStream};
 use zatel::{
-    ipc_bind_with_path, ipc_recv, ipc_send, ZatelError, ZatelIpcData,
-    ZatelIpcMessage, ZatelPluginCapacity, ZatelPluginInfo,
+    init_logging, run_plugin, ZatelError, ZatelIpcData, ZatelIpcMessage,
+    ZatelPlugin, ZatelPluginCapacity, ZatelWireFormat,
 };
 
 use crate::iface::ZatelBaseIface;
 
 const PLUGIN_NAME: &str = "nispor";
+const PLUGIN_IDENTITY_PATH: &str = "/etc/zatel/plugins/nispor.key";
 
-#[tokio::main()]
-async fn main() {
-    let argv: Vec<String> = args().collect();
+struct NisporPlugin;
 
-    if argv.len() != 2 {
-        eprintln!(
-            "Invalid argument, should be single argument: <plugin_socket_path>"
-        );
-        std::process::exit(1);
+#[async_trait]
+impl ZatelPlugin for NisporPlugin {
+    fn name(&self) -> &str {
+        PLUGIN_NAME
     }
 
-    let socket_path = &argv[1];
+    fn identity_path(&self) -> &str {
+        PLUGIN_IDENTITY_PATH
+    }
 
-    let listener = match ipc_bind_with_path(socket_path) {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("{}", e);
-            return;
-        }
-    };
-    eprintln!("DEBUG: {}: listening on {}", PLUGIN_NAME, socket_path);
-
-    loop {
-        match listener.accept().await {
-            Ok((stream, _)) => {
-                // TODO: Limit the maximum connected client as it could
-                //       from suspicious source, not daemon
-                tokio::task::spawn(async move { handle_client(stream).await });
-            }
-            Err(e) => {
-                eprintln!("{}", e);
-            }
-        }
+    fn capacities(&self) -> Vec<ZatelPluginCapacity> {
+        vec![ZatelPluginCapacity::Query, ZatelPluginCapacity::Apply]
     }
-}
 
-async fn shutdown_connection(stream: &mut UnixStream) {
-    if let Err(e) = stream.shutdown().await {
-        eprintln!("{}", e);
+    async fn query_iface(
+        &self,
+        iface_name: &str,
+        format: ZatelWireFormat,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        query_iface(iface_name, format)
     }
-}
 
-// TODO: Implement on:
-//  * timeout
-async fn handle_client(mut stream: UnixStream) {
-    loop {
-        match ipc_recv(&mut stream).await {
-            Ok(ipc_msg) => match ipc_msg.data {
-                ZatelIpcData::ConnectionClosed => {
-                    shutdown_connection(&mut stream).await;
-                    break;
-                }
-                _ => {
-                    let message = handle_msg(ipc_msg.data).await;
-                    eprintln!("DEBUG: {}: reply: {:?}", PLUGIN_NAME, &message);
-                    if let Err(e) = ipc_send(&mut stream, &message).await {
-                        eprintln!(
-                            "{}: failed to send to daemon : {}",
-                            PLUGIN_NAME, e
-                        );
-                    }
-                }
-            },
-            Err(e) => {
-                eprintln!("IPC error {}", e);
-                shutdown_connection(&mut stream).await;
-                break;
-            }
-        }
+    async fn validate_conf(
+        &self,
+        conf: &str,
+    ) -> Result<ZatelIpcMessage, ZatelError> {
+        validate_conf(conf)
     }
 }
 
-// TODO: The lib zatel should provide function call `plugin_start` taking
-//       below function pointer as argument. But it is complex to passing
-//       async function to a thread.
-async fn handle_msg(data: ZatelIpcData) -> ZatelIpcMessage {
-    eprintln!("DEBUG: {}: Got request: {:?}", PLUGIN_NAME, data);
-    match data {
-        ZatelIpcData::QueryIfaceInfo(iface_name) => {
-            ZatelIpcMessage::from_result(query_iface(&iface_name))
-        }
-        ZatelIpcData::QueryPluginInfo => ZatelIpcMessage::new(
-            ZatelIpcData::QueryPluginInfoReply(ZatelPluginInfo::new(
-                PLUGIN_NAME,
-                vec![ZatelPluginCapacity::Query, ZatelPluginCapacity::Apply],
-            )),
-        ),
-        ZatelIpcData::ValidateConf(conf) => {
-            ZatelIpcMessage::from_result(validate_conf(&conf))
-        }
-        _ => {
-            eprintln!(
-                "WARN: {}: Got unknown request: {:?}",
-                PLUGIN_NAME, &data
-            );
-            ZatelIpcMessage::new(ZatelIpcData::None)
-        }
+#[tokio::main()]
+async fn main() {
+    if let Err(e) = init_logging("zatel_plugin_nispor") {
+        eprintln!("{}", e);
+        std::process::exit(1);
     }
+
+    let argv: Vec<String> = args().collect();
+
+    if argv.len() != 3 {
+        error!(
+            "Invalid arguments, should be: <plugin_socket_path> \
+            <daemon_pubkey_hex>"
+        );
+        std::process::exit(1);
+    }
+
+    run_plugin(NisporPlugin, &argv[1], &argv[2]).await;
 }
 
-fn query_iface(iface_name: &str) -> Result<ZatelIpcMessage, ZatelError> {
+fn query_iface(
+    iface_name: &str,
+    format: ZatelWireFormat,
+) -> Result<ZatelIpcMessage, ZatelError> {
     let net_state = match NetState::retrieve() {
         Ok(s) => s,
         Err(e) => {
@@ -141,15 +99,25 @@ fn query_iface(iface_name: &str) -> Result<ZatelIpcMessage, ZatelError> {
     match net_state.ifaces.get(iface_name) {
         Some(iface_info) => {
             let zatel_iface: ZatelBaseIface = iface_info.into();
-            match serde_yaml::to_string(&zatel_iface) {
-                Ok(s) => Ok(ZatelIpcMessage::new(
-                    ZatelIpcData::QueryIfaceInfoReply(s),
-                )),
-                Err(e) => Err(ZatelError::plugin_error(format!(
-                    "Failed to convert ZatelIfaceInfo to yml: {}",
-                    e
-                ))),
-            }
+            let serialized = match format {
+                ZatelWireFormat::Yaml => serde_yaml::to_string(&zatel_iface)
+                    .map_err(|e| {
+                        ZatelError::plugin_error(format!(
+                            "Failed to convert ZatelIfaceInfo to yaml: {}",
+                            e
+                        ))
+                    }),
+                ZatelWireFormat::Json => serde_json::to_string(&zatel_iface)
+                    .map_err(|e| {
+                        ZatelError::plugin_error(format!(
+                            "Failed to convert ZatelIfaceInfo to json: {}",
+                            e
+                        ))
+                    }),
+            };
+            serialized.map(|s| {
+                ZatelIpcMessage::new(ZatelIpcData::QueryIfaceInfoReply(s))
+            })
         }
         None => Err(ZatelError::invalid_argument(format!(
             "Interface {} not found",